@@ -0,0 +1,79 @@
+//! Migrations written in Rust
+use std::fmt;
+#[cfg(not(any(
+    feature = "postgres_support",
+    feature = "sqlite_support",
+    feature = "mysql_support",
+    feature = "surreal_support"
+)))]
+use std::marker::PhantomData;
+
+use crate::errors::Result;
+use crate::files::Direction;
+
+/// The connection of the driver running a code migration.
+/// Unless the migration is told otherwise, it is already in a transaction that also
+/// records the migration in the history so it shouldn't commit or start one itself.
+pub enum Connection<'a> {
+    /// Given by the Postgres driver
+    #[cfg(feature = "postgres_support")]
+    Postgres(&'a mut postgres_client::Client),
+    /// Given by the SQLite driver
+    #[cfg(feature = "sqlite_support")]
+    Sqlite(&'a sqlite_client::Connection),
+    /// Given by the MySQL driver
+    #[cfg(feature = "mysql_support")]
+    Mysql(&'a mut mysql_client::PooledConn),
+    /// Given by the SurrealDB driver, which never runs migrations in a transaction.
+    /// The client is async so queries need to be ran with the runtime.
+    #[cfg(feature = "surreal_support")]
+    Surrealdb {
+        /// The client of the driver
+        client: &'a surreal_client::Surreal<surreal_client::engine::remote::ws::Client>,
+        /// The runtime the client runs on
+        runtime: &'a tokio::runtime::Runtime,
+    },
+    /// Without any driver there is nothing to connect to
+    #[cfg(not(any(
+        feature = "postgres_support",
+        feature = "sqlite_support",
+        feature = "mysql_support",
+        feature = "surreal_support"
+    )))]
+    #[doc(hidden)]
+    None(PhantomData<&'a ()>),
+}
+
+/// A migration written in Rust, for changes SQL can't express such as backfilling
+/// data with business logic.
+/// It is given a number and a name with `Migration::new_code` and is then ordered,
+/// ran and recorded in the migration history like the ones read from files.
+pub trait CodeMigration {
+    /// Applies the migration
+    fn up(&self, connection: Connection) -> Result<()>;
+    /// Reverts the migration
+    fn down(&self, connection: Connection) -> Result<()>;
+    /// Whether the migration can be reverted: if not, reverting it errors before
+    /// anything is ran like it does for SQL migrations without a down file
+    fn has_down(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for dyn CodeMigration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CodeMigration")
+    }
+}
+
+/// Runs the given direction of a code migration
+pub(crate) fn run(
+    migration: &dyn CodeMigration,
+    direction: Direction,
+    connection: Connection,
+) -> Result<()> {
+    match direction {
+        Direction::Up => migration.up(connection),
+        Direction::Down => migration.down(connection),
+    }
+}
@@ -1,7 +1,12 @@
-///! Driver interface and implementations
+//! Driver interface and implementations
+use std::thread;
+use std::time::{Duration, Instant};
+
 use url::Url;
 
 use crate::errors::{Result, ResultExt};
+use crate::files::{Direction, Migration};
+use crate::history::{self, HistoryEntry};
 
 #[cfg(feature = "mysql_support")]
 pub mod mysql;
@@ -12,36 +17,276 @@ pub mod sqlite;
 #[cfg(feature = "surreal_support")]
 pub mod surreal;
 
+/// Name of the table storing the migration history when none is given
+pub const DEFAULT_TABLE_NAME: &str = "__dbmigrate_table";
+
+/// Query string parameters of a database URL configuring the migration table,
+/// removed from the URL before connecting
+const TABLE_URL_PARAM: &str = "dbmigrate_table";
+const SCHEMA_URL_PARAM: &str = "dbmigrate_schema";
+
+/// Where a driver stores the migration history, so that several applications
+/// can share a database.
+/// Names are limited to letters, digits and underscores as they end up in queries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationTable {
+    name: String,
+    schema: Option<String>,
+}
+
+fn validate_identifier(identifier: &str) -> Result<()> {
+    let valid = identifier
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && identifier
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        bail!(
+            "Invalid migration table name `{}`: only letters, digits and underscores are allowed",
+            identifier
+        );
+    }
+    Ok(())
+}
+
+impl MigrationTable {
+    /// A table with the given name, in the default schema
+    pub fn new(name: &str) -> Result<MigrationTable> {
+        validate_identifier(name)?;
+        Ok(MigrationTable {
+            name: name.to_string(),
+            schema: None,
+        })
+    }
+
+    /// Puts the table in the given schema, only supported by Postgres
+    pub fn schema(mut self, schema: &str) -> Result<MigrationTable> {
+        validate_identifier(schema)?;
+        self.schema = Some(schema.to_string());
+        Ok(self)
+    }
+
+    /// The table name, without the schema
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The schema of the table if one was given
+    pub fn schema_name(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    /// The name to use in queries, including the schema
+    pub(crate) fn qualified(&self) -> String {
+        match self.schema {
+            Some(ref schema) => format!("{}.{}", schema, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// The table used as a lock by databases without named locks
+    pub(crate) fn lock_table(&self) -> String {
+        format!("{}_lock", self.qualified())
+    }
+
+    /// Errors for the databases that can't put the table in a schema
+    pub(crate) fn ensure_no_schema(&self, database: &str) -> Result<()> {
+        if self.schema.is_some() {
+            bail!("{} doesn't support a migration table schema", database);
+        }
+        Ok(())
+    }
+
+    /// Extracts the migration table configured in the query string of `url`,
+    /// returning the URL without those parameters
+    fn from_url(url: &str) -> Result<(String, Option<MigrationTable>)> {
+        let parsed = Url::parse(url).chain_err(|| format!("Invalid URL: {}", url))?;
+        let mut name = None;
+        let mut schema = None;
+        let mut others = Vec::new();
+        for (key, value) in parsed.query_pairs() {
+            match key.as_ref() {
+                TABLE_URL_PARAM => name = Some(value.into_owned()),
+                SCHEMA_URL_PARAM => schema = Some(value.into_owned()),
+                _ => others.push((key.into_owned(), value.into_owned())),
+            }
+        }
+        if name.is_none() && schema.is_none() {
+            // Left untouched as parsing normalizes URLs
+            return Ok((url.to_string(), None));
+        }
+
+        let mut stripped = parsed.clone();
+        if others.is_empty() {
+            stripped.set_query(None);
+        } else {
+            stripped.query_pairs_mut().clear().extend_pairs(others);
+        }
+        let mut table = MigrationTable::new(name.as_deref().unwrap_or(DEFAULT_TABLE_NAME))?;
+        if let Some(schema) = schema {
+            table = table.schema(&schema)?;
+        }
+        Ok((stripped.to_string(), Some(table)))
+    }
+}
+
+impl Default for MigrationTable {
+    fn default() -> MigrationTable {
+        MigrationTable {
+            name: DEFAULT_TABLE_NAME.to_string(),
+            schema: None,
+        }
+    }
+}
+
+/// Where the migration table stands, which tells what
+/// `ensure_migration_table_exists` has to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TableState {
+    /// There is no migration table yet
+    Missing,
+    /// The `(id, current)` table of older versions of dbmigrate, with its current number
+    Legacy(u64),
+    /// A history table missing columns added since it was created.
+    /// `get_history` can still read it.
+    Outdated,
+    /// Nothing to do
+    Ready,
+}
+
 /// The common trait that all databases need to implement in order
 /// for migrations to work
 pub trait Driver {
-    /// A fn that will create a migration table if it doesn't exist
-    /// Otherwise do nothing
-    fn ensure_migration_table_exists(&mut self);
+    /// A fn that will create a migration table if it doesn't exist, upgrading
+    /// the ones of older versions of dbmigrate. Otherwise do nothing.
+    /// The `Migrator` calls it while holding the lock before touching the table.
+    fn ensure_migration_table_exists(&mut self) -> Result<()>;
+    /// Where the migration table stands, without changing anything
+    fn migration_table_state(&mut self) -> Result<TableState>;
     /// A fn that will delete migration table
-    fn remove_migration_table(&mut self);
+    fn remove_migration_table(&mut self) -> Result<()>;
+    /// Get all the entries of the migration history, oldest first
+    fn get_history(&mut self) -> Result<Vec<HistoryEntry>>;
+    /// Add an entry at the end of the migration history
+    fn add_history_entry(&mut self, entry: &HistoryEntry) -> Result<()>;
     /// Get the current migration number from the database
-    fn get_current_number(&mut self) -> u32;
-    /// Set the current migration number in the database
-    fn set_current_number(&mut self, number: u32);
-    /// Perform the `migration` content on the database and set
-    /// the migration number to be the `number` given
-    fn migrate(&mut self, migration: String, number: u32) -> Result<()>;
+    fn get_current_number(&mut self) -> Result<u64> {
+        Ok(history::current_number(&self.get_history()?))
+    }
+    /// Perform the `migration` content for the given `direction` on the
+    /// database and record it in the migration history
+    fn migrate(&mut self, migration: &Migration, direction: Direction) -> Result<()>;
+    /// Whether `migrate` runs the given direction of `migration` in a transaction,
+    /// a failure then leaving nothing behind
+    fn in_transaction(&self, migration: &Migration, direction: Direction) -> bool {
+        migration.in_transaction(direction)
+    }
+    /// Acquire the lock preventing several processes from migrating the same
+    /// database at once, waiting at most `timeout` for it to be released
+    fn lock(&mut self, timeout: Duration) -> Result<()>;
+    /// Release the lock acquired by `lock`
+    fn unlock(&mut self) -> Result<()>;
+    /// Release the lock left behind by a process killed while holding it.
+    /// Databases releasing locks when the connection holding them closes have
+    /// nothing to do.
+    fn release_stale_lock(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Calls `try_lock` until it manages to acquire the lock or `timeout` is elapsed,
+/// for databases that can't wait on a lock by themselves
+fn wait_for_lock<F>(timeout: Duration, mut try_lock: F) -> Result<()>
+where
+    F: FnMut() -> Result<bool>,
+{
+    let start = Instant::now();
+    loop {
+        if try_lock()? {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            bail!(
+                "Timed out after {} second(s) waiting for the migration lock",
+                timeout.as_secs()
+            );
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
 }
 
-/// Returns a driver instance depending on url
+/// Returns a driver instance depending on url.
+/// The migration table can be set with the `dbmigrate_table` and, for Postgres,
+/// `dbmigrate_schema` parameters of the URL query string.
 pub fn get_driver(url: &str) -> Result<Box<dyn Driver>> {
+    let (url, table) = MigrationTable::from_url(url)?;
+    connect(&url, table.unwrap_or_default())
+}
+
+/// Returns a driver instance depending on url, storing the migration history
+/// in `table` whatever the URL query string says
+pub fn get_driver_with_table(url: &str, table: MigrationTable) -> Result<Box<dyn Driver>> {
+    let (url, _) = MigrationTable::from_url(url)?;
+    connect(&url, table)
+}
+
+/// The scheme of a database URL, which picks the driver `get_driver` returns.
+/// Migration files specific to that driver are tagged with it.
+pub fn driver_scheme(url: &str) -> Result<String> {
     let parsed_url = Url::parse(url).chain_err(|| format!("Invalid URL: {}", url))?;
+    Ok(parsed_url.scheme().to_string())
+}
 
-    match parsed_url.scheme() {
+fn connect(url: &str, table: MigrationTable) -> Result<Box<dyn Driver>> {
+    match driver_scheme(url)?.as_str() {
         #[cfg(feature = "postgres_support")]
-        "postgres" => postgres::Postgres::new(url).map(|d| Box::new(d) as Box<dyn Driver>),
+        "postgres" => postgres::Postgres::new(url, table).map(|d| Box::new(d) as Box<dyn Driver>),
         #[cfg(feature = "mysql_support")]
-        "mysql" => mysql::Mysql::new(url).map(|d| Box::new(d) as Box<dyn Driver>),
+        "mysql" => mysql::Mysql::new(url, table).map(|d| Box::new(d) as Box<dyn Driver>),
         #[cfg(feature = "sqlite_support")]
-        "sqlite" => sqlite::Sqlite::new(url).map(|d| Box::new(d) as Box<dyn Driver>),
+        "sqlite" => sqlite::Sqlite::new(url, table).map(|d| Box::new(d) as Box<dyn Driver>),
         #[cfg(feature = "surreal_support")]
-        "surreal" => surreal::Surrealdb::new(url).map(|d| Box::new(d) as Box<dyn Driver>),
+        "surreal" => surreal::Surrealdb::new(url, table).map(|d| Box::new(d) as Box<dyn Driver>),
         _ => bail!("Invalid URL: {}", url),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{DEFAULT_TABLE_NAME, MigrationTable};
+
+    #[test]
+    fn test_table_from_url() {
+        let url = "postgres://localhost/app?sslmode=disable";
+        assert_eq!(
+            MigrationTable::from_url(url).unwrap(),
+            (url.to_string(), None)
+        );
+
+        let (url, table) = MigrationTable::from_url(
+            "postgres://localhost/app?dbmigrate_table=billing_migrations&sslmode=disable&dbmigrate_schema=billing",
+        )
+        .unwrap();
+        assert_eq!(url, "postgres://localhost/app?sslmode=disable");
+        let table = table.unwrap();
+        assert_eq!(table.name(), "billing_migrations");
+        assert_eq!(table.qualified(), "billing.billing_migrations");
+
+        let (url, table) =
+            MigrationTable::from_url("sqlite:///tmp/app.db?dbmigrate_schema=billing").unwrap();
+        assert_eq!(url, "sqlite:///tmp/app.db");
+        assert_eq!(table.unwrap().name(), DEFAULT_TABLE_NAME);
+    }
+
+    #[test]
+    fn test_table_names_are_validated() {
+        assert!(MigrationTable::new("_app_migrations2").is_ok());
+        assert!(MigrationTable::new("").is_err());
+        assert!(MigrationTable::new("2migrations").is_err());
+        assert!(MigrationTable::new("migrations; DROP TABLE users").is_err());
+        assert!(MigrationTable::default().schema("my-schema").is_err());
+        assert!(MigrationTable::from_url("postgres://localhost/app?dbmigrate_table=a.b").is_err());
+    }
+}
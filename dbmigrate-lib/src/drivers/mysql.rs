@@ -1,74 +1,468 @@
-use mysql_client::{Pool, from_row};
+use std::time::{Duration, Instant};
 
-use super::Driver;
-use crate::errors::{Result, ResultExt};
+use mysql_client::prelude::GenericConnection;
+use mysql_client::{Error as MysqlError, Pool, PooledConn, Row, from_row_opt};
+use regex::Regex;
+
+use super::{Driver, MigrationTable, TableState};
+use crate::code::{self, CodeMigration};
+use crate::errors::{Error, ErrorKind, Result, ResultExt};
+use crate::files::{Direction, Migration};
+use crate::history::{HistoryEntry, entry_from_row, legacy_entries};
 
 /// The MySQL driver
 #[derive(Debug)]
 pub struct Mysql {
     pool: Pool,
+    /// MySQL locks belong to a connection so we keep the one holding it
+    /// out of the pool until it is released
+    lock_conn: Option<PooledConn>,
+    table: MigrationTable,
 }
 
 impl Mysql {
-    /// Create MySQL driver
-    pub fn new(url: &str) -> Result<Mysql> {
-        let pool = Pool::new(url)?;
-        let mut mysql = Mysql { pool: pool };
-        mysql.ensure_migration_table_exists();
-
-        Ok(mysql)
+    /// Create MySQL driver storing the migration history in `table`
+    pub fn new(url: &str, table: MigrationTable) -> Result<Mysql> {
+        table.ensure_no_schema("MySQL")?;
+        let pool = Pool::new(url).map_err(classify)?;
+        Ok(Mysql {
+            pool,
+            lock_conn: None,
+            table,
+        })
     }
-}
 
-impl Driver for Mysql {
-    fn ensure_migration_table_exists(&mut self) {
-        let mut conn = self.pool.get_conn().unwrap();
-        conn.query(
-            "
-            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER);
-            INSERT INTO __dbmigrate_table (id, current)
-            SELECT 1, 0 FROM DUAL
-            WHERE NOT EXISTS(SELECT * FROM __dbmigrate_table WHERE id = 1);
+    /// The column names of the given table, empty if it doesn't exist
+    fn columns(&mut self, table: &str) -> Result<Vec<String>> {
+        let result = self
+            .pool
+            .prep_exec(
+                "
+            SELECT column_name FROM information_schema.columns
+            WHERE table_schema = DATABASE() AND table_name = ?;
         ",
-        )
-        .unwrap();
+                (table,),
+            )
+            .map_err(classify)?;
+        result
+            .map(|row| {
+                from_row_opt::<String>(row.map_err(classify)?)
+                    .map_err(|_| "Invalid column name in information_schema".into())
+            })
+            .collect()
+    }
+
+    /// Whether the migration table has the given column
+    fn has_column(&mut self, column: &str) -> Result<bool> {
+        let table = self.table.qualified();
+        Ok(self.columns(&table)?.iter().any(|c| c == column))
+    }
+
+    /// Where a legacy table is moved while the new migration table is filled
+    fn renamed_legacy_table(&self) -> String {
+        format!("{}_legacy", self.table.qualified())
+    }
+
+    /// Older versions of dbmigrate only stored the current number in a
+    /// `(id, current)` table: if `table` is one, return that number
+    fn legacy_current_number(&mut self, table: &str) -> Result<Option<u64>> {
+        if !self.columns(table)?.iter().any(|c| c == "current") {
+            return Ok(None);
+        }
+
+        let row = self
+            .pool
+            .first_exec(format!("SELECT current FROM {} WHERE id = 1;", table), ())
+            .map_err(classify)?;
+        let current = match row {
+            Some(row) => from_row_opt::<Option<u64>>(row)
+                .map_err(|_| ErrorKind::CorruptMigrationTable("invalid current number".into()))?,
+            None => None,
+        };
+        Ok(Some(current.unwrap_or(0)))
     }
 
-    fn remove_migration_table(&mut self) {
+    /// Fills the new migration table from the renamed legacy one, if any, and
+    /// drops the latter. A run stopped midway is picked up by the next one:
+    /// the entries are inserted at once, only in a table without any.
+    fn finish_legacy_upgrade(&mut self) -> Result<()> {
+        let legacy = self.renamed_legacy_table();
+        let current = match self.legacy_current_number(&legacy)? {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+
+        let mut transaction = self
+            .pool
+            .start_transaction(false, None, None)
+            .map_err(classify)?;
+        let count = transaction
+            .first_exec(
+                format!("SELECT COUNT(*) FROM {};", self.table.qualified()),
+                (),
+            )
+            .map_err(classify)?
+            .and_then(|row| from_row_opt::<u64>(row).ok())
+            .unwrap_or(0);
+        if count == 0 {
+            for entry in legacy_entries(current) {
+                insert_history_entry(&mut transaction, &self.table, &entry)?;
+            }
+        }
+        transaction.commit().map_err(classify)?;
+
         self.pool
-            .prep_exec("DROP TABLE __dbmigrate_table;", ())
+            .prep_exec(format!("DROP TABLE {};", legacy), ())
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    /// Runs a migration written in Rust. It gets a connection of the pool so the
+    /// transaction is started and ended with statements
+    fn migrate_code(
+        &mut self,
+        migration_code: &dyn CodeMigration,
+        migration: &Migration,
+        direction: Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut conn = self.pool.get_conn().map_err(classify)?;
+        let in_transaction = migration.in_transaction(direction);
+        if in_transaction {
+            run_statements(&mut conn, "START TRANSACTION;")?;
+        }
+        let res = code::run(
+            migration_code,
+            direction,
+            code::Connection::Mysql(&mut conn),
+        )
+        .chain_err(|| "Migration failed")
+        .and_then(|_| {
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            insert_history_entry(&mut conn, &self.table, &entry)
+        });
+        if !in_transaction {
+            return res;
+        }
+        match res {
+            Ok(()) => run_statements(&mut conn, "COMMIT;"),
+            Err(e) => {
+                // The migration error is more interesting than a failed rollback
+                let _ = run_statements(&mut conn, "ROLLBACK;");
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Gives a more specific kind to the errors a library user might want to handle
+fn classify(e: MysqlError) -> Error {
+    let kind = match e {
+        MysqlError::IoError(_) | MysqlError::DriverError(_) => Some(ErrorKind::ConnectionLost),
+        // Access denied to a database, table, column or for a specific privilege
+        MysqlError::MySqlError(ref err) if [1044, 1045, 1142, 1143, 1227].contains(&err.code) => {
+            Some(ErrorKind::PermissionDenied)
+        }
+        _ => None,
+    };
+    match kind {
+        Some(kind) => Error::with_chain(e, kind),
+        None => e.into(),
+    }
+}
+
+/// MySQL commits the current transaction implicitly before and after
+/// DDL statements so migrations containing them can't be rolled back
+fn has_implicit_commit(content: &str) -> bool {
+    // Comments are removed so they don't hide the statement following them and
+    // string literals are emptied so what they contain isn't taken for a statement
+    let comments =
+        Regex::new(r#"(?s)'(?:[^'\\]|\\.)*'|"(?:[^"\\]|\\.)*"|--[^\n]*|#[^\n]*|/\*.*?\*/"#)
             .unwrap();
+    let content = comments.replace_all(content, |caps: &regex::Captures| {
+        if caps[0].starts_with('\'') || caps[0].starts_with('"') {
+            "''"
+        } else {
+            " "
+        }
+    });
+    let re = Regex::new(
+        r"(?i)(^|;)\s*(CREATE|ALTER|DROP|RENAME|TRUNCATE|GRANT|REVOKE|LOCK\s+TABLES|UNLOCK\s+TABLES)\b",
+    )
+    .unwrap();
+    re.is_match(&content)
+}
+
+/// Runs all the statements of `content`: errors happening after the first
+/// statement are only reported when reading the following result sets
+fn run_statements<C: GenericConnection>(conn: &mut C, content: &str) -> Result<()> {
+    let mut result = conn.query(content).map_err(classify)?;
+    while result.more_results_exists() {
+        for row in result.by_ref() {
+            row.map_err(classify)?;
+        }
     }
+    Ok(())
+}
 
-    fn get_current_number(&mut self) -> u32 {
-        let mut result = self
-            .pool
+fn insert_history_entry<C: GenericConnection>(
+    conn: &mut C,
+    table: &MigrationTable,
+    entry: &HistoryEntry,
+) -> Result<()> {
+    conn.prep_exec(
+        format!(
+            "
+        INSERT INTO {}
+        (number, name, applied_at, duration_ms, checksum, direction, kind)
+        VALUES (?, ?, ?, ?, ?, ?, ?);
+    ",
+            table.qualified()
+        ),
+        (
+            entry.number,
+            &entry.name,
+            entry.applied_at,
+            entry.duration_ms,
+            &entry.checksum,
+            entry.direction.to_string(),
+            entry.kind.to_string(),
+        ),
+    )
+    .map_err(classify)?;
+    Ok(())
+}
+
+fn parse_history_row(row: Row) -> Result<HistoryEntry> {
+    let (number, name, applied_at, duration_ms, checksum, direction, kind) = from_row_opt::<(
+        i64,
+        String,
+        i64,
+        i64,
+        Option<String>,
+        String,
+        Option<String>,
+    )>(row)
+    .map_err(|_| ErrorKind::CorruptMigrationTable("invalid row".to_string()))?;
+    entry_from_row(
+        number,
+        name,
+        applied_at,
+        duration_ms,
+        checksum,
+        &direction,
+        kind.as_deref(),
+    )
+}
+
+impl Driver for Mysql {
+    fn ensure_migration_table_exists(&mut self) -> Result<()> {
+        // MySQL commits implicitly around DDL statements so a legacy table is
+        // renamed rather than dropped, keeping its number until the new table has it
+        let table = self.table.qualified();
+        if self.legacy_current_number(&table)?.is_some() {
+            self.pool
+                .prep_exec(
+                    format!("RENAME TABLE {} TO {};", table, self.renamed_legacy_table()),
+                    (),
+                )
+                .map_err(classify)?;
+        }
+        self.pool
             .prep_exec(
-                "
-            SELECT current FROM __dbmigrate_table WHERE id = 1;
+                format!(
+                    "
+            CREATE TABLE IF NOT EXISTS {}(
+                id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+                number BIGINT NOT NULL,
+                name VARCHAR(255) NOT NULL,
+                applied_at BIGINT NOT NULL,
+                duration_ms BIGINT NOT NULL,
+                checksum VARCHAR(255),
+                direction VARCHAR(16) NOT NULL,
+                kind VARCHAR(16)
+            );
         ",
+                    self.table.qualified()
+                ),
                 (),
             )
-            .unwrap();
-        // That is quite ugly
-        let row = result.next().unwrap();
-        from_row::<u32>(row.unwrap())
+            .map_err(classify)?;
+        // Tables created before kinds were recorded don't have the column
+        if !self.has_column("kind")? {
+            self.pool
+                .prep_exec(
+                    format!(
+                        "ALTER TABLE {} ADD COLUMN kind VARCHAR(16);",
+                        self.table.qualified()
+                    ),
+                    (),
+                )
+                .map_err(classify)?;
+        }
+        self.finish_legacy_upgrade()
     }
 
-    fn set_current_number(&mut self, number: u32) {
+    fn remove_migration_table(&mut self) -> Result<()> {
         self.pool
+            .prep_exec(format!("DROP TABLE {};", self.table.qualified()), ())
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    fn migration_table_state(&mut self) -> Result<TableState> {
+        // An upgrade stopped midway leaves the renamed legacy table behind
+        let legacy = self.renamed_legacy_table();
+        if let Some(current) = self.legacy_current_number(&legacy)? {
+            return Ok(TableState::Legacy(current));
+        }
+        let table = self.table.qualified();
+        let columns = self.columns(&table)?;
+        if columns.is_empty() {
+            return Ok(TableState::Missing);
+        }
+        if let Some(current) = self.legacy_current_number(&table)? {
+            return Ok(TableState::Legacy(current));
+        }
+        if !columns.iter().any(|c| c == "kind") {
+            return Ok(TableState::Outdated);
+        }
+        Ok(TableState::Ready)
+    }
+
+    fn get_history(&mut self) -> Result<Vec<HistoryEntry>> {
+        // Tables that weren't upgraded yet don't have the kind column
+        let kind = if self.has_column("kind")? {
+            "kind"
+        } else {
+            "NULL"
+        };
+        let result = self
+            .pool
             .prep_exec(
-                "UPDATE __dbmigrate_table SET current = ? WHERE id = 1;",
-                (&number,),
+                format!(
+                    "
+            SELECT number, name, applied_at, duration_ms, checksum, direction, {}
+            FROM {} ORDER BY id;
+        ",
+                    kind,
+                    self.table.qualified()
+                ),
+                (),
             )
-            .unwrap();
+            .map_err(classify)?;
+
+        result
+            .map(|row| parse_history_row(row.map_err(classify)?))
+            .collect()
+    }
+
+    fn add_history_entry(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let mut conn = self.pool.get_conn().map_err(classify)?;
+        insert_history_entry(&mut conn, &self.table, entry)
+    }
+
+    fn migrate(&mut self, migration: &Migration, direction: Direction) -> Result<()> {
+        if let Some(ref migration_code) = migration.code {
+            return self.migrate_code(&**migration_code, migration, direction);
+        }
+        let start = Instant::now();
+        let content = migration.get_content(direction)?;
+
+        if self.in_transaction(migration, direction) {
+            let mut transaction = self
+                .pool
+                .start_transaction(false, None, None)
+                .map_err(classify)?;
+            run_statements(&mut transaction, content).chain_err(|| "Migration failed")?;
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            insert_history_entry(&mut transaction, &self.table, &entry)?;
+            transaction.commit().map_err(classify)?;
+        } else {
+            let mut conn = self.pool.get_conn().map_err(classify)?;
+            run_statements(&mut conn, content).chain_err(|| {
+                "Migration failed: it ran outside of a transaction \
+                 so it might have been partially applied"
+            })?;
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            insert_history_entry(&mut conn, &self.table, &entry)?;
+        }
+
+        Ok(())
     }
 
-    fn migrate(&mut self, migration: String, number: u32) -> Result<()> {
-        let mut conn = self.pool.get_conn()?;
-        conn.query(migration).chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+    fn in_transaction(&self, migration: &Migration, direction: Direction) -> bool {
+        if !migration.in_transaction(direction) {
+            return false;
+        }
+        match migration.get_content(direction) {
+            Ok(content) => !has_implicit_commit(content),
+            // Code migrations
+            Err(_) => true,
+        }
+    }
 
+    fn lock(&mut self, timeout: Duration) -> Result<()> {
+        let mut conn = self.pool.get_conn().map_err(classify)?;
+        // Lock names are global to the server, hence the database name
+        let acquired: Option<Option<i64>> = conn
+            .first_exec(
+                "SELECT GET_LOCK(CONCAT(DATABASE(), '.', ?), ?);",
+                (self.table.name(), timeout.as_secs()),
+            )
+            .map_err(classify)?;
+        match acquired {
+            Some(Some(1)) => {
+                self.lock_conn = Some(conn);
+                Ok(())
+            }
+            Some(Some(0)) => bail!(
+                "Timed out after {} second(s) waiting for the migration lock",
+                timeout.as_secs()
+            ),
+            _ => bail!("Failed to acquire the migration lock"),
+        }
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        if let Some(mut conn) = self.lock_conn.take() {
+            conn.prep_exec(
+                "SELECT RELEASE_LOCK(CONCAT(DATABASE(), '.', ?));",
+                (self.table.name(),),
+            )
+            .map_err(classify)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::has_implicit_commit;
+
+    #[test]
+    fn test_detect_implicit_commit() {
+        assert!(has_implicit_commit("CREATE TABLE users (id INT);"));
+        assert!(has_implicit_commit(
+            "INSERT INTO users VALUES (1);\n  alter table users add name text;"
+        ));
+        assert!(!has_implicit_commit(
+            "INSERT INTO users (name) VALUES ('DROP TABLE'); UPDATE users SET created = 1;"
+        ));
+        assert!(has_implicit_commit(
+            "-- Adds the users table\nCREATE TABLE users (id INT);"
+        ));
+        assert!(has_implicit_commit(
+            "/* Adds the users table */ CREATE TABLE users (id INT);"
+        ));
+        assert!(has_implicit_commit(
+            "INSERT INTO users VALUES (1);\n-- then the column\nALTER TABLE users ADD name TEXT;"
+        ));
+        assert!(has_implicit_commit(
+            "INSERT INTO users VALUES (1); # then the column\nALTER TABLE users ADD name TEXT;"
+        ));
+        assert!(!has_implicit_commit(
+            "INSERT INTO users (name) VALUES ('-- not a comment; DROP TABLE users');"
+        ));
+    }
+}
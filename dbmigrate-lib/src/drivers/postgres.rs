@@ -1,81 +1,358 @@
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use native_tls::TlsConnector;
-use postgres_client::{Client, Config};
+use postgres_client::error::SqlState;
+use postgres_client::{Client, Config, GenericClient, Row};
 use postgres_native_tls::MakeTlsConnector;
+use sha2::{Digest, Sha256};
 
-use super::Driver;
-use crate::errors::{Result, ResultExt};
+use super::{Driver, MigrationTable, TableState, wait_for_lock};
+use crate::code::{self, CodeMigration};
+use crate::errors::{Error, ErrorKind, Result, ResultExt};
+use crate::files::{Direction, Migration};
+use crate::history::{HistoryEntry, entry_from_row, legacy_entries};
 
 /// The PostgreSQL driver
 //#[derive(Debug)]
 pub struct Postgres {
     client: Client,
+    table: MigrationTable,
 }
 
 impl Postgres {
-    /// Create PostgreSQL driver
-    pub fn new(url: &str) -> Result<Postgres> {
+    /// Create PostgreSQL driver storing the migration history in `table`
+    pub fn new(url: &str, table: MigrationTable) -> Result<Postgres> {
         let config = Config::from_str(url)?;
-        let connector = TlsConnector::new().unwrap();
+        let connector = TlsConnector::new().chain_err(|| "Failed to create TLS connector")?;
         let connector = MakeTlsConnector::new(connector);
-        let client = config.connect(connector)?;
-        Postgres::from_client(client)
+        // Errors without a SQL state never reached the server
+        let client = config.connect(connector).map_err(|e| match e.code() {
+            Some(_) => classify(e),
+            None => Error::with_chain(e, ErrorKind::ConnectionLost),
+        })?;
+        Postgres::from_client(client, table)
     }
     /// Create PostgreSQL driver using an existing client
-    pub fn from_client(client: Client) -> Result<Postgres> {
-        let mut pg = Postgres { client };
-        pg.ensure_migration_table_exists();
-        Ok(pg)
+    pub fn from_client(client: Client, table: MigrationTable) -> Result<Postgres> {
+        Ok(Postgres { client, table })
     }
+
+    /// Runs a migration written in Rust. It gets the client itself so the
+    /// transaction is started and ended with statements
+    fn migrate_code(
+        &mut self,
+        migration_code: &dyn CodeMigration,
+        migration: &Migration,
+        direction: Direction,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let in_transaction = migration.in_transaction(direction);
+        if in_transaction {
+            self.client.batch_execute("BEGIN;").map_err(classify)?;
+        }
+        let res = code::run(
+            migration_code,
+            direction,
+            code::Connection::Postgres(&mut self.client),
+        )
+        .chain_err(|| "Migration failed")
+        .and_then(|_| {
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            insert_history_entry(&mut self.client, &self.table, &entry)
+        });
+        if !in_transaction {
+            return res;
+        }
+        match res {
+            Ok(()) => self.client.batch_execute("COMMIT;").map_err(classify),
+            Err(e) => {
+                // The migration error is more interesting than a failed rollback
+                let _ = self.client.batch_execute("ROLLBACK;");
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Gives a more specific kind to the errors a library user might want to handle
+fn classify(e: postgres_client::Error) -> Error {
+    if e.is_closed() {
+        Error::with_chain(e, ErrorKind::ConnectionLost)
+    } else if e.code() == Some(&SqlState::INSUFFICIENT_PRIVILEGE) {
+        Error::with_chain(e, ErrorKind::PermissionDenied)
+    } else {
+        e.into()
+    }
+}
+
+/// The column names of the migration table, empty if it doesn't exist
+fn columns<C: GenericClient>(client: &mut C, table: &MigrationTable) -> Result<Vec<String>> {
+    let rows = client
+        .query(
+            "
+            SELECT column_name::text FROM information_schema.columns
+            WHERE table_schema = COALESCE($1::text, current_schema())
+            AND table_name = $2::text;
+        ",
+            &[&table.schema_name(), &table.name()],
+        )
+        .map_err(classify)?;
+    Ok(rows.iter().map(|row| row.get(0)).collect())
+}
+
+/// Older versions of dbmigrate only stored the current number in a
+/// `(id, current)` table: if we find one, return that number
+fn legacy_current_number<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+) -> Result<Option<u64>> {
+    if !columns(client, table)?.iter().any(|c| c == "current") {
+        return Ok(None);
+    }
+
+    let rows = client
+        .query(
+            &format!("SELECT current FROM {} WHERE id = 1;", table.qualified()),
+            &[],
+        )
+        .map_err(classify)?;
+    let current: Option<i32> = match rows.first() {
+        Some(row) => row
+            .try_get("current")
+            .chain_err(|| ErrorKind::CorruptMigrationTable("invalid current number".into()))?,
+        None => None,
+    };
+    Ok(Some(current.unwrap_or(0) as u64))
+}
+
+/// Same as `legacy_current_number`, dropping the legacy table
+fn take_legacy_current_number<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+) -> Result<Option<u64>> {
+    let current = legacy_current_number(client, table)?;
+    if current.is_some() {
+        client
+            .batch_execute(&format!("DROP TABLE {};", table.qualified()))
+            .map_err(classify)?;
+    }
+    Ok(current)
+}
+
+/// Key of the advisory lock, derived from the migration table name so that
+/// it is unlikely to collide with the ones an application would use
+fn advisory_lock_key(table: &MigrationTable) -> i64 {
+    let digest = Sha256::digest(table.qualified().as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(bytes)
+}
+
+fn insert_history_entry<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    entry: &HistoryEntry,
+) -> Result<()> {
+    client
+        .execute(
+            &format!(
+                "
+            INSERT INTO {}
+            (number, name, applied_at, duration_ms, checksum, direction, kind)
+            VALUES ($1, $2, $3, $4, $5, $6, $7);
+        ",
+                table.qualified()
+            ),
+            &[
+                &(entry.number as i64),
+                &entry.name,
+                &(entry.applied_at as i64),
+                &(entry.duration_ms as i64),
+                &entry.checksum,
+                &entry.direction.to_string(),
+                &entry.kind.to_string(),
+            ],
+        )
+        .map_err(classify)?;
+    Ok(())
+}
+
+fn parse_history_row(row: &Row) -> Result<HistoryEntry> {
+    let invalid = |e| {
+        Error::with_chain(
+            e,
+            ErrorKind::CorruptMigrationTable("invalid row".to_string()),
+        )
+    };
+    let direction: String = row.try_get("direction").map_err(invalid)?;
+    let kind: Option<String> = row.try_get("kind").map_err(invalid)?;
+    entry_from_row(
+        row.try_get("number").map_err(invalid)?,
+        row.try_get("name").map_err(invalid)?,
+        row.try_get("applied_at").map_err(invalid)?,
+        row.try_get("duration_ms").map_err(invalid)?,
+        row.try_get("checksum").map_err(invalid)?,
+        &direction,
+        kind.as_deref(),
+    )
 }
 
 impl Driver for Postgres {
-    fn ensure_migration_table_exists(&mut self) {
-        self.client
-            .simple_query(
+    fn ensure_migration_table_exists(&mut self) -> Result<()> {
+        let mut transaction = self.client.transaction().map_err(classify)?;
+        if let Some(schema) = self.table.schema_name() {
+            transaction
+                .batch_execute(&format!("CREATE SCHEMA IF NOT EXISTS {};", schema))
+                .map_err(classify)?;
+        }
+        let legacy_current = take_legacy_current_number(&mut transaction, &self.table)?;
+        transaction
+            .batch_execute(&format!(
                 "
-            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER);
-            INSERT INTO __dbmigrate_table (id, current)
-            SELECT 1, 0
-            WHERE NOT EXISTS(SELECT * FROM __dbmigrate_table WHERE id = 1);
+            CREATE TABLE IF NOT EXISTS {0}(
+                id SERIAL PRIMARY KEY,
+                number BIGINT NOT NULL,
+                name TEXT NOT NULL,
+                applied_at BIGINT NOT NULL,
+                duration_ms BIGINT NOT NULL,
+                checksum TEXT,
+                direction TEXT NOT NULL,
+                kind TEXT
+            );
+            -- Tables created before kinds were recorded don't have the column
+            ALTER TABLE {0} ADD COLUMN IF NOT EXISTS kind TEXT;
         ",
-            )
-            .unwrap();
+                self.table.qualified()
+            ))
+            .map_err(classify)?;
+        if let Some(current) = legacy_current {
+            for entry in legacy_entries(current) {
+                insert_history_entry(&mut transaction, &self.table, &entry)?;
+            }
+        }
+        transaction.commit().map_err(classify)
     }
 
-    fn remove_migration_table(&mut self) {
+    fn remove_migration_table(&mut self) -> Result<()> {
         self.client
-            .execute("DROP TABLE __dbmigrate_table;", &[])
-            .unwrap();
+            .execute(&format!("DROP TABLE {};", self.table.qualified()), &[])
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    fn migration_table_state(&mut self) -> Result<TableState> {
+        let columns = columns(&mut self.client, &self.table)?;
+        if columns.is_empty() {
+            return Ok(TableState::Missing);
+        }
+        if let Some(current) = legacy_current_number(&mut self.client, &self.table)? {
+            return Ok(TableState::Legacy(current));
+        }
+        if !columns.iter().any(|c| c == "kind") {
+            return Ok(TableState::Outdated);
+        }
+        Ok(TableState::Ready)
     }
 
-    fn get_current_number(&mut self) -> u32 {
-        let stmt = self
+    fn get_history(&mut self) -> Result<Vec<HistoryEntry>> {
+        // Tables that weren't upgraded yet don't have the kind column
+        let kind = if columns(&mut self.client, &self.table)?
+            .iter()
+            .any(|c| c == "kind")
+        {
+            "kind"
+        } else {
+            "NULL::text AS kind"
+        };
+        let rows = self
             .client
-            .prepare(
-                "
-            SELECT current FROM __dbmigrate_table WHERE id = 1;
+            .query(
+                &format!(
+                    "
+            SELECT number, name, applied_at, duration_ms, checksum, direction, {}
+            FROM {} ORDER BY id;
         ",
+                    kind,
+                    self.table.qualified()
+                ),
+                &[],
             )
-            .unwrap();
-        let results = self.client.query(&stmt, &[]).unwrap();
-        results.first().unwrap().get("current")
+            .map_err(classify)?;
+
+        rows.iter().map(parse_history_row).collect()
     }
 
-    fn set_current_number(&mut self, number: u32) {
-        let stmt = self
-            .client
-            .prepare("UPDATE __dbmigrate_table SET current = $1 WHERE id = 1;")
-            .unwrap();
-        self.client.execute(&stmt, &[&number]).unwrap();
+    fn add_history_entry(&mut self, entry: &HistoryEntry) -> Result<()> {
+        insert_history_entry(&mut self.client, &self.table, entry)
     }
 
-    fn migrate(&mut self, migration: String, number: u32) -> Result<()> {
-        self.client
-            .simple_query(&migration)
+    fn migrate(&mut self, migration: &Migration, direction: Direction) -> Result<()> {
+        if let Some(ref migration_code) = migration.code {
+            return self.migrate_code(&**migration_code, migration, direction);
+        }
+        let start = Instant::now();
+        let content = migration.get_content(direction)?;
+
+        if !migration.in_transaction(direction) {
+            // Postgres still runs a multi-statement query in an implicit transaction
+            // so statements like CREATE INDEX CONCURRENTLY need to be alone in their file
+            self.client
+                .simple_query(content)
+                .map_err(classify)
+                .chain_err(|| "Migration failed")?;
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            return insert_history_entry(&mut self.client, &self.table, &entry);
+        }
+
+        // The transaction is rolled back when dropped so a failing migration
+        // leaves both the schema and the history untouched
+        let mut transaction = self.client.transaction().map_err(classify)?;
+        transaction
+            .simple_query(content)
+            .map_err(classify)
             .chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+        let entry = HistoryEntry::new(migration, direction, start.elapsed());
+        insert_history_entry(&mut transaction, &self.table, &entry)?;
+        transaction.commit().map_err(classify)
+    }
+
+    fn lock(&mut self, timeout: Duration) -> Result<()> {
+        let key = advisory_lock_key(&self.table);
+        wait_for_lock(timeout, || {
+            let row = self
+                .client
+                .query_one("SELECT pg_try_advisory_lock($1);", &[&key])
+                .map_err(classify)?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        self.client
+            .query_one(
+                "SELECT pg_advisory_unlock($1);",
+                &[&advisory_lock_key(&self.table)],
+            )
+            .map_err(classify)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Postgres;
+    use crate::drivers::MigrationTable;
+    use crate::errors::{Error, ErrorKind};
+
+    #[test]
+    fn test_unreachable_host() {
+        // Nothing listens on port 1
+        let url = "postgres://postgres@127.0.0.1:1/dbmigrate?connect_timeout=5";
+        match Postgres::new(url, MigrationTable::default()) {
+            Err(Error(ErrorKind::ConnectionLost, _)) => (),
+            Err(e) => panic!("expected a connection lost error, got {:?}", e),
+            Ok(_) => panic!("expected a connection lost error, got a connection"),
+        }
+    }
+}
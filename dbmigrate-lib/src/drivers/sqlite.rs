@@ -1,71 +1,505 @@
-use sqlite_client::Connection;
+use std::time::{Duration, Instant};
 
-use super::Driver;
-use crate::errors::{Result, ResultExt};
+use sqlite_client::{Connection, Error as SqliteError, ErrorCode, Row};
+
+use super::{Driver, MigrationTable, TableState, wait_for_lock};
+use crate::code;
+use crate::errors::{Error, ErrorKind, Result, ResultExt};
+use crate::files::{Direction, Migration};
+use crate::history::{self, HistoryEntry, entry_from_row, legacy_entries};
 
 /// The SQLite driver
 #[derive(Debug)]
 pub struct Sqlite {
     conn: Connection,
+    table: MigrationTable,
 }
 
 impl Sqlite {
-    /// Create SQLite driver
-    pub fn new(url: &str) -> Result<Sqlite> {
+    /// Create SQLite driver storing the migration history in `table`
+    pub fn new(url: &str, table: MigrationTable) -> Result<Sqlite> {
         // the replace is probably wrong
-        let conn = Connection::open(url.replace("sqlite:/", ""))?;
-        let mut sqlite = Sqlite { conn: conn };
-        sqlite.ensure_migration_table_exists();
-        Ok(sqlite)
+        let conn = Connection::open(url.replace("sqlite:/", "")).map_err(classify)?;
+        Sqlite::from_connection(conn, table)
+    }
+
+    /// Create SQLite driver using an existing connection
+    pub fn from_connection(conn: Connection, table: MigrationTable) -> Result<Sqlite> {
+        table.ensure_no_schema("SQLite")?;
+        Ok(Sqlite { conn, table })
+    }
+}
+
+/// Gives a more specific kind to the errors a library user might want to handle.
+/// There is no connection to lose with SQLite, only files we might not be allowed to touch.
+fn classify(e: SqliteError) -> Error {
+    let denied = match e {
+        SqliteError::SqliteFailure(ref failure, _) => matches!(
+            failure.code,
+            ErrorCode::PermissionDenied
+                | ErrorCode::ReadOnly
+                | ErrorCode::AuthorizationForStatementDenied
+        ),
+        _ => false,
+    };
+    if denied {
+        Error::with_chain(e, ErrorKind::PermissionDenied)
+    } else {
+        e.into()
     }
 }
 
+/// Creates the table holding the lock row if it doesn't exist
+fn ensure_lock_table_exists(conn: &Connection, table: &MigrationTable) -> Result<()> {
+    conn.execute_batch(&format!(
+        "
+        CREATE TABLE IF NOT EXISTS {}(
+            id INTEGER PRIMARY KEY,
+            locked_at INTEGER NOT NULL
+        );
+    ",
+        table.lock_table()
+    ))
+    .map_err(classify)
+}
+
+/// The column names of the migration table, empty if it doesn't exist
+fn columns(conn: &Connection, table: &MigrationTable) -> Result<Vec<String>> {
+    let mut stmt = conn
+        .prepare(&format!("PRAGMA table_info({});", table.qualified()))
+        .map_err(classify)?;
+    let rows = stmt.query_map(&[], |row| row.get(1)).map_err(classify)?;
+    rows.collect::<std::result::Result<_, _>>()
+        .map_err(classify)
+}
+
+/// Older versions of dbmigrate only stored the current number in a
+/// `(id, current)` table: if we find one, return that number
+fn legacy_current_number(conn: &Connection, table: &MigrationTable) -> Result<Option<u64>> {
+    if !columns(conn, table)?
+        .iter()
+        .any(|column| column == "current")
+    {
+        return Ok(None);
+    }
+
+    let current: Option<u32> = conn
+        .query_row(
+            &format!("SELECT current FROM {} WHERE id = 1;", table.qualified()),
+            &[],
+            |row| row.get_checked(0),
+        )
+        .map_err(classify)?
+        .chain_err(|| ErrorKind::CorruptMigrationTable("invalid current number".into()))?;
+    Ok(Some(current.map_or(0, u64::from)))
+}
+
+/// Same as `legacy_current_number`, dropping the legacy table
+fn take_legacy_current_number(conn: &Connection, table: &MigrationTable) -> Result<Option<u64>> {
+    let current = legacy_current_number(conn, table)?;
+    if current.is_some() {
+        conn.execute_batch(&format!("DROP TABLE {};", table.qualified()))
+            .map_err(classify)?;
+    }
+    Ok(current)
+}
+
+/// Runs the content of the migration, or its code if it is written in Rust
+fn run_migration(conn: &Connection, migration: &Migration, direction: Direction) -> Result<()> {
+    match migration.code {
+        Some(ref migration_code) => {
+            code::run(&**migration_code, direction, code::Connection::Sqlite(conn))
+        }
+        None => conn
+            .execute_batch(migration.get_content(direction)?)
+            .map_err(classify),
+    }
+}
+
+fn insert_history_entry(
+    conn: &Connection,
+    table: &MigrationTable,
+    entry: &HistoryEntry,
+) -> Result<()> {
+    conn.execute(
+        &format!(
+            "
+        INSERT INTO {}
+        (number, name, applied_at, duration_ms, checksum, direction, kind)
+        VALUES (?, ?, ?, ?, ?, ?, ?);
+    ",
+            table.qualified()
+        ),
+        &[
+            &(entry.number as i64),
+            &entry.name,
+            &(entry.applied_at as i64),
+            &(entry.duration_ms as i64),
+            &entry.checksum,
+            &entry.direction.to_string(),
+            &entry.kind.to_string(),
+        ],
+    )
+    .map_err(classify)?;
+    Ok(())
+}
+
+fn parse_history_row(row: &Row) -> Result<HistoryEntry> {
+    let invalid = |e| {
+        Error::with_chain(
+            e,
+            ErrorKind::CorruptMigrationTable("invalid row".to_string()),
+        )
+    };
+    let direction: String = row.get_checked(5).map_err(invalid)?;
+    let kind: Option<String> = row.get_checked(6).map_err(invalid)?;
+    entry_from_row(
+        row.get_checked(0).map_err(invalid)?,
+        row.get_checked(1).map_err(invalid)?,
+        row.get_checked(2).map_err(invalid)?,
+        row.get_checked(3).map_err(invalid)?,
+        row.get_checked(4).map_err(invalid)?,
+        &direction,
+        kind.as_deref(),
+    )
+}
+
 impl Driver for Sqlite {
-    fn ensure_migration_table_exists(&mut self) {
-        self.conn
-            .execute_batch(
+    fn ensure_migration_table_exists(&mut self) -> Result<()> {
+        let transaction = self.conn.transaction().map_err(classify)?;
+        let legacy_current = take_legacy_current_number(&transaction, &self.table)?;
+        transaction
+            .execute_batch(&format!(
                 "
-            CREATE TABLE IF NOT EXISTS __dbmigrate_table(id INTEGER, current INTEGER);
-            INSERT INTO __dbmigrate_table (id, current)
-            SELECT 1, 0
-            WHERE NOT EXISTS(SELECT * FROM __dbmigrate_table WHERE id = 1);
+            CREATE TABLE IF NOT EXISTS {}(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                number INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                checksum TEXT,
+                direction TEXT NOT NULL,
+                kind TEXT
+            );
         ",
-            )
-            .unwrap();
+                self.table.qualified()
+            ))
+            .map_err(classify)?;
+        // Tables created before kinds were recorded don't have the column
+        if !columns(&transaction, &self.table)?
+            .iter()
+            .any(|c| c == "kind")
+        {
+            transaction
+                .execute_batch(&format!(
+                    "ALTER TABLE {} ADD COLUMN kind TEXT;",
+                    self.table.qualified()
+                ))
+                .map_err(classify)?;
+        }
+        if let Some(current) = legacy_current {
+            for entry in legacy_entries(current) {
+                insert_history_entry(&transaction, &self.table, &entry)?;
+            }
+        }
+        transaction.commit().map_err(classify)
     }
 
-    fn remove_migration_table(&mut self) {
+    fn remove_migration_table(&mut self) -> Result<()> {
         self.conn
-            .execute("DROP TABLE __dbmigrate_table;", &[])
-            .unwrap();
+            .execute(&format!("DROP TABLE {};", self.table.qualified()), &[])
+            .map_err(classify)?;
+        Ok(())
     }
 
-    fn get_current_number(&mut self) -> u32 {
-        self.conn
-            .query_row(
+    fn migration_table_state(&mut self) -> Result<TableState> {
+        let columns = columns(&self.conn, &self.table)?;
+        if columns.is_empty() {
+            return Ok(TableState::Missing);
+        }
+        if let Some(current) = legacy_current_number(&self.conn, &self.table)? {
+            return Ok(TableState::Legacy(current));
+        }
+        if !columns.iter().any(|c| c == "kind") {
+            return Ok(TableState::Outdated);
+        }
+        Ok(TableState::Ready)
+    }
+
+    fn get_history(&mut self) -> Result<Vec<HistoryEntry>> {
+        // Tables that weren't upgraded yet don't have the kind column
+        let kind = if columns(&self.conn, &self.table)?
+            .iter()
+            .any(|c| c == "kind")
+        {
+            "kind"
+        } else {
+            "NULL"
+        };
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
                 "
-            SELECT current FROM __dbmigrate_table WHERE id = 1;
+            SELECT number, name, applied_at, duration_ms, checksum, direction, {}
+            FROM {} ORDER BY id;
+        ",
+                kind,
+                self.table.qualified()
+            ))
+            .map_err(classify)?;
+        let mut rows = stmt.query(&[]).map_err(classify)?;
+
+        let mut history = Vec::new();
+        while let Some(row) = rows.next() {
+            history.push(parse_history_row(&row.map_err(classify)?)?);
+        }
+        Ok(history)
+    }
+
+    fn add_history_entry(&mut self, entry: &HistoryEntry) -> Result<()> {
+        insert_history_entry(&self.conn, &self.table, entry)
+    }
+
+    fn migrate(&mut self, migration: &Migration, direction: Direction) -> Result<()> {
+        let start = Instant::now();
+
+        if !migration.in_transaction(direction) {
+            run_migration(&self.conn, migration, direction).chain_err(|| "Migration failed")?;
+            let entry = HistoryEntry::new(migration, direction, start.elapsed());
+            return insert_history_entry(&self.conn, &self.table, &entry);
+        }
+
+        // Rolled back on drop if anything below fails
+        let transaction = self.conn.transaction().map_err(classify)?;
+        run_migration(&transaction, migration, direction).chain_err(|| "Migration failed")?;
+        let entry = HistoryEntry::new(migration, direction, start.elapsed());
+        insert_history_entry(&transaction, &self.table, &entry)?;
+        transaction.commit().map_err(classify)
+    }
+
+    fn lock(&mut self, timeout: Duration) -> Result<()> {
+        // SQLite has no named locks so we use a table with at most one row:
+        // a process dying while holding it leaves the row behind until
+        // `release_stale_lock` is called
+        ensure_lock_table_exists(&self.conn, &self.table)?;
+        wait_for_lock(timeout, || {
+            let inserted = self
+                .conn
+                .execute(
+                    &format!(
+                        "INSERT OR IGNORE INTO {} (id, locked_at) VALUES (1, ?);",
+                        self.table.lock_table()
+                    ),
+                    &[&(history::now() as i64)],
+                )
+                .map_err(classify)?;
+            Ok(inserted == 1)
+        })
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        self.conn
+            .execute(
+                &format!("DELETE FROM {} WHERE id = 1;", self.table.lock_table()),
+                &[],
+            )
+            .map_err(classify)?;
+        Ok(())
+    }
+
+    fn release_stale_lock(&mut self) -> Result<()> {
+        ensure_lock_table_exists(&self.conn, &self.table)?;
+        self.unlock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sqlite;
+    use crate::drivers::{Driver, MigrationTable};
+    use crate::errors::{Error, ErrorKind};
+    use crate::files::fixtures::migration;
+    use crate::files::{Direction, Migration};
+    use crate::history::EntryKind;
+    use sqlite_client::Connection;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn memory_driver() -> Sqlite {
+        let mut driver = Sqlite::from_connection(
+            Connection::open_in_memory().unwrap(),
+            MigrationTable::default(),
+        )
+        .unwrap();
+        driver.ensure_migration_table_exists().unwrap();
+        driver
+    }
+
+    #[test]
+    fn test_migrate_records_history() {
+        let mut driver = memory_driver();
+        assert_eq!(driver.get_current_number().unwrap(), 0);
+
+        driver.migrate(&migration(1), Direction::Up).unwrap();
+        driver.migrate(&migration(2), Direction::Up).unwrap();
+        driver.migrate(&migration(2), Direction::Down).unwrap();
+
+        let history = driver.get_history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0].number, 1);
+        assert_eq!(history[0].name, "table_1");
+        assert_eq!(history[2].direction, Direction::Down);
+        assert_eq!(driver.get_current_number().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_upgrade_legacy_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE __dbmigrate_table(id INTEGER, current INTEGER);
+            INSERT INTO __dbmigrate_table (id, current) VALUES (1, 3);
         ",
+        )
+        .unwrap();
+
+        let mut driver = Sqlite::from_connection(conn, MigrationTable::default()).unwrap();
+        driver.ensure_migration_table_exists().unwrap();
+        let history = driver.get_history().unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|e| e.direction == Direction::Up));
+        assert_eq!(driver.get_current_number().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_upgrade_table_without_kind() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE __dbmigrate_table(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                number INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                applied_at INTEGER NOT NULL,
+                duration_ms INTEGER NOT NULL,
+                checksum TEXT,
+                direction TEXT NOT NULL
+            );
+            INSERT INTO __dbmigrate_table
+            (number, name, applied_at, duration_ms, checksum, direction)
+            VALUES (1, 'users', 0, 0, NULL, 'up');
+        ",
+        )
+        .unwrap();
+
+        let mut driver = Sqlite::from_connection(conn, MigrationTable::default()).unwrap();
+        driver.ensure_migration_table_exists().unwrap();
+        driver.migrate(&migration(2), Direction::Up).unwrap();
+        let history = driver.get_history().unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|e| e.kind == EntryKind::Ran));
+    }
+
+    #[test]
+    fn test_failed_migration_is_rolled_back() {
+        let mut driver = memory_driver();
+        driver.migrate(&migration(1), Direction::Up).unwrap();
+
+        let broken = Migration::new(
+            "CREATE TABLE broken (id INTEGER); INSERT INTO nope VALUES (1);".to_string(),
+            None,
+            2,
+            "broken".to_string(),
+        );
+        assert!(driver.migrate(&broken, Direction::Up).is_err());
+        assert_eq!(driver.get_current_number().unwrap(), 1);
+        assert_eq!(driver.get_history().unwrap().len(), 1);
+        let tables: i64 = driver
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'broken';",
                 &[],
                 |row| row.get(0),
             )
-            .unwrap()
+            .unwrap();
+        assert_eq!(tables, 0);
     }
 
-    fn set_current_number(&mut self, number: u32) {
-        let mut stmt = self
+    #[test]
+    fn test_migration_without_transaction() {
+        let mut driver = memory_driver();
+        // VACUUM can't run inside a transaction
+        let vacuum = Migration::new("VACUUM;".to_string(), None, 1, "vacuum".to_string());
+        assert!(driver.migrate(&vacuum, Direction::Up).is_err());
+
+        let vacuum = Migration::new(
+            "-- dbmigrate:no-transaction\nVACUUM;".to_string(),
+            None,
+            1,
+            "vacuum".to_string(),
+        );
+        driver.migrate(&vacuum, Direction::Up).unwrap();
+        assert_eq!(driver.get_current_number().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_corrupt_history() {
+        let mut driver = memory_driver();
+        driver
             .conn
-            .prepare("UPDATE __dbmigrate_table SET current = ? WHERE id = 1;")
+            .execute_batch(
+                "
+            INSERT INTO __dbmigrate_table
+            (number, name, applied_at, duration_ms, checksum, direction)
+            VALUES (1, 'users', 0, 0, NULL, 'sideways');
+        ",
+            )
             .unwrap();
-        stmt.execute(&[&number]).unwrap();
+
+        match driver.get_current_number() {
+            Err(Error(ErrorKind::CorruptMigrationTable(_), _)) => (),
+            res => panic!("expected a corrupt migration table error, got {:?}", res),
+        }
     }
 
-    fn migrate(&mut self, migration: String, number: u32) -> Result<()> {
-        self.conn
-            .execute_batch(&migration)
-            .chain_err(|| "Migration failed")?;
-        self.set_current_number(number);
+    #[test]
+    fn test_lock() {
+        let dir = TempDir::new("sqlite").unwrap();
+        let url = format!("sqlite:/{}", dir.path().join("test.db").display());
+        let mut first = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        let mut second = Sqlite::new(&url, MigrationTable::default()).unwrap();
 
-        Ok(())
+        first.lock(Duration::from_secs(0)).unwrap();
+        assert!(second.lock(Duration::from_secs(0)).is_err());
+        first.unlock().unwrap();
+        second.lock(Duration::from_secs(0)).unwrap();
+
+        // As if the process holding it was killed
+        drop(second);
+        first.release_stale_lock().unwrap();
+        first.lock(Duration::from_secs(0)).unwrap();
+        first.unlock().unwrap();
+    }
+
+    #[test]
+    fn test_custom_table() {
+        let dir = TempDir::new("sqlite").unwrap();
+        let url = format!("sqlite:/{}", dir.path().join("test.db").display());
+        let mut first = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        let mut second = Sqlite::new(&url, MigrationTable::new("other_app").unwrap()).unwrap();
+        first.ensure_migration_table_exists().unwrap();
+        second.ensure_migration_table_exists().unwrap();
+
+        first.migrate(&migration(1), Direction::Up).unwrap();
+        assert_eq!(first.get_current_number().unwrap(), 1);
+        assert_eq!(second.get_current_number().unwrap(), 0);
+
+        // Each table has its own lock
+        first.lock(Duration::from_secs(0)).unwrap();
+        second.lock(Duration::from_secs(0)).unwrap();
+
+        let schema = MigrationTable::new("other_app").unwrap().schema("app");
+        let conn = Connection::open_in_memory().unwrap();
+        assert!(Sqlite::from_connection(conn, schema.unwrap()).is_err());
     }
 }
@@ -1,21 +1,30 @@
+use std::time::{Duration, Instant};
+
 use surreal_client::Surreal;
 use surreal_client::engine::remote::ws::{Client, Ws};
+use surreal_client::error::{Api, Db};
+use surreal_client::method::Query;
 use tokio::runtime::Runtime;
 use url::Url;
 
-use super::Driver;
-use crate::errors::{Result, ResultExt};
+use super::{Driver, MigrationTable, TableState, wait_for_lock};
+use crate::code;
+use crate::errors::{Error, ErrorKind, Result, ResultExt};
+use crate::files::{Direction, Migration};
+use crate::history::{HistoryEntry, entry_from_row, legacy_entries};
 
 /// The SurrealDB driver
 #[derive(Debug)]
 pub struct Surrealdb {
     client: Surreal<Client>,
     runtime: Runtime,
+    table: MigrationTable,
 }
 
 impl Surrealdb {
-    /// Create SurrealDB driver
-    pub fn new(url: &str) -> Result<Surrealdb> {
+    /// Create SurrealDB driver storing the migration history in `table`
+    pub fn new(url: &str, table: MigrationTable) -> Result<Surrealdb> {
+        table.ensure_no_schema("SurrealDB")?;
         let runtime =
             Runtime::new().chain_err(|| format!("Tokio runtime failed to start: {}", url))?;
 
@@ -71,74 +80,283 @@ impl Surrealdb {
             })
             .chain_err(|| "Failed to create SurrealDB client")?;
 
-        let mut surrealdb = Surrealdb { client, runtime };
+        Ok(Surrealdb {
+            client,
+            runtime,
+            table,
+        })
+    }
 
-        surrealdb.ensure_migration_table_exists();
+    /// Older versions of dbmigrate only stored the current number in a
+    /// `__dbmigrate_table:1` record: if we find one, return that number
+    fn legacy_current_number(&mut self) -> Result<Option<u64>> {
+        let query = format!(
+            "SELECT VALUE current FROM ONLY {}:1;",
+            self.table.qualified()
+        );
+        self.runtime.block_on(async {
+            let mut result = self.client.query(query).await.map_err(classify)?;
+            result
+                .take::<Option<u64>>(0)
+                .chain_err(|| ErrorKind::CorruptMigrationTable("invalid current number".into()))
+        })
+    }
+}
 
-        Ok(surrealdb)
+/// The statement adding an entry at the end of the history, its values bound by
+/// `bind_entry` with the same `index` so a single query can add several
+fn create_entry(table: &str, index: usize) -> String {
+    format!(
+        r#"
+            LET $entries = SELECT VALUE id FROM {table};
+            CREATE {table} SET
+                position = array::len($entries) + 1,
+                number = $number_{index},
+                name = $name_{index},
+                applied_at = $applied_at_{index},
+                duration_ms = $duration_ms_{index},
+                checksum = $checksum_{index},
+                direction = $direction_{index},
+                kind = $kind_{index};
+        "#,
+        table = table,
+        index = index
+    )
+}
+
+fn bind_entry<'r>(
+    query: Query<'r, Client>,
+    index: usize,
+    entry: &HistoryEntry,
+) -> Query<'r, Client> {
+    query
+        .bind((format!("number_{}", index), entry.number))
+        .bind((format!("name_{}", index), entry.name.clone()))
+        .bind((format!("applied_at_{}", index), entry.applied_at))
+        .bind((format!("duration_ms_{}", index), entry.duration_ms))
+        .bind((format!("checksum_{}", index), entry.checksum.clone()))
+        .bind((format!("direction_{}", index), entry.direction.to_string()))
+        .bind((format!("kind_{}", index), entry.kind.to_string()))
+}
+
+/// Gives a more specific kind to the errors a library user might want to handle
+fn classify(e: surreal_client::Error) -> Error {
+    let kind = match e {
+        surreal_client::Error::Api(Api::Ws(_) | Api::Http(_) | Api::ConnectionUninitialised) => {
+            Some(ErrorKind::ConnectionLost)
+        }
+        surreal_client::Error::Db(
+            Db::IamError(_) | Db::InvalidAuth | Db::NsNotAllowed { .. } | Db::DbNotAllowed { .. },
+        ) => Some(ErrorKind::PermissionDenied),
+        _ => None,
+    };
+    match kind {
+        Some(kind) => Error::with_chain(e, kind),
+        None => e.into(),
     }
 }
 
 impl Driver for Surrealdb {
-    fn ensure_migration_table_exists(&mut self) {
+    fn ensure_migration_table_exists(&mut self) -> Result<()> {
+        // A legacy table is replaced in a transaction so its number isn't lost
+        // if something fails before the new table is filled
+        let legacy_current = self.legacy_current_number()?;
+        let entries = legacy_current.map(legacy_entries).unwrap_or_default();
+
+        let table = self.table.qualified();
+        let mut sql = String::from("BEGIN TRANSACTION;");
+        if legacy_current.is_some() {
+            sql.push_str(&format!("REMOVE TABLE {};", table));
+        }
+        sql.push_str(&format!(
+            r#"
+                DEFINE TABLE IF NOT EXISTS {table} SCHEMAFULL;
+                DEFINE FIELD IF NOT EXISTS position ON TABLE {table} TYPE int;
+                DEFINE FIELD IF NOT EXISTS number ON TABLE {table} TYPE int;
+                DEFINE FIELD IF NOT EXISTS name ON TABLE {table} TYPE string;
+                DEFINE FIELD IF NOT EXISTS applied_at ON TABLE {table} TYPE int;
+                DEFINE FIELD IF NOT EXISTS duration_ms ON TABLE {table} TYPE int;
+                DEFINE FIELD IF NOT EXISTS checksum ON TABLE {table} TYPE option<string>;
+                DEFINE FIELD IF NOT EXISTS direction ON TABLE {table} TYPE string;
+                DEFINE FIELD IF NOT EXISTS kind ON TABLE {table} TYPE option<string>;
+            "#,
+            table = table
+        ));
+        for index in 0..entries.len() {
+            sql.push_str(&create_entry(&table, index));
+        }
+        sql.push_str("COMMIT TRANSACTION;");
+
         self.runtime.block_on(async {
-            let query = r#"
-                DEFINE TABLE IF NOT EXISTS __dbmigrate_table SCHEMAFULL;
-                DEFINE FIELD IF NOT EXISTS id ON TABLE __dbmigrate_table TYPE int;
-                DEFINE FIELD IF NOT EXISTS current ON TABLE __dbmigrate_table TYPE int;
-
-                LET $initialMigration = (SELECT * FROM ONLY __dbmigrate_table:1);
-                IF !$initialMigration {
-                    CREATE __dbmigrate_table:1 SET current = 0;
-                }
-            "#;
+            let mut query = self.client.query(sql);
+            for (index, entry) in entries.iter().enumerate() {
+                query = bind_entry(query, index, entry);
+            }
+            query
+                .await
+                .and_then(|response| response.check())
+                .map_err(classify)?;
+            Ok(())
+        })
+    }
 
-            self.client.query(query).await.unwrap();
-        });
+    fn migration_table_state(&mut self) -> Result<TableState> {
+        let query = format!(
+            "RETURN object::keys((INFO FOR DB).tables) CONTAINS '{}';",
+            self.table.qualified()
+        );
+        let exists = self.runtime.block_on(async {
+            let mut result = self.client.query(query).await.map_err(classify)?;
+            result.take::<Option<bool>>(0).map_err(classify)
+        })?;
+        if exists != Some(true) {
+            return Ok(TableState::Missing);
+        }
+        if let Some(current) = self.legacy_current_number()? {
+            return Ok(TableState::Legacy(current));
+        }
+        // Fields missing from older tables are read as NONE, adding them
+        // can wait for the next command writing to the table
+        Ok(TableState::Ready)
     }
 
-    fn remove_migration_table(&mut self) {
+    fn remove_migration_table(&mut self) -> Result<()> {
+        let query = format!("REMOVE TABLE {};", self.table.qualified());
         self.runtime.block_on(async {
-            let query = "REMOVE TABLE __dbmigrate_table;";
-            self.client.query(query).await.unwrap();
-        });
+            self.client
+                .query(query)
+                .await
+                .and_then(|response| response.check())
+                .map_err(classify)?;
+            Ok(())
+        })
     }
 
-    fn get_current_number(&mut self) -> u32 {
-        self.runtime.block_on(async {
-            let query = r#"
-                let $record = SELECT current FROM ONLY __dbmigrate_table:1;
-                RETURN $record.current;
-            "#;
-            let mut result = self.client.query(query).await.unwrap();
+    fn get_history(&mut self) -> Result<Vec<HistoryEntry>> {
+        let query = format!(
+            r#"
+                SELECT VALUE [position, number, name, applied_at, duration_ms, checksum, direction, kind]
+                FROM {};
+            "#,
+            self.table.qualified()
+        );
+        let mut rows = self.runtime.block_on(async {
+            let mut result = self.client.query(query).await.map_err(classify)?;
 
-            let current = result.take::<Option<u32>>(1).unwrap();
+            result
+                .take::<Vec<(
+                    i64,
+                    i64,
+                    String,
+                    i64,
+                    i64,
+                    Option<String>,
+                    String,
+                    Option<String>,
+                )>>(0)
+                .chain_err(|| ErrorKind::CorruptMigrationTable("invalid row".to_string()))
+        })?;
+        // Record ids are random so the order is kept in a separate field
+        rows.sort_by_key(|row| row.0);
 
-            current.unwrap_or(0)
-        })
+        rows.into_iter()
+            .map(
+                |(_, number, name, applied_at, duration_ms, checksum, direction, kind)| {
+                    entry_from_row(
+                        number,
+                        name,
+                        applied_at,
+                        duration_ms,
+                        checksum,
+                        &direction,
+                        kind.as_deref(),
+                    )
+                },
+            )
+            .collect()
     }
 
-    fn set_current_number(&mut self, number: u32) {
+    fn add_history_entry(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let query = create_entry(&self.table.qualified(), 0);
         self.runtime.block_on(async {
-            let query = "UPDATE __dbmigrate_table:1 SET current = $number;";
-            self.client
-                .query(query)
-                .bind(("number", number))
+            bind_entry(self.client.query(query), 0, entry)
                 .await
-                .unwrap();
-        });
+                .and_then(|response| response.check())
+                .map_err(classify)?;
+            Ok(())
+        })
     }
 
-    fn migrate(&mut self, migration: String, number: u32) -> Result<()> {
+    fn migrate(&mut self, migration: &Migration, direction: Direction) -> Result<()> {
+        let start = Instant::now();
+        if let Some(ref migration_code) = migration.code {
+            let connection = code::Connection::Surrealdb {
+                client: &self.client,
+                runtime: &self.runtime,
+            };
+            code::run(&**migration_code, direction, connection).chain_err(|| "Migration failed")?;
+            return self.add_history_entry(&HistoryEntry::new(
+                migration,
+                direction,
+                start.elapsed(),
+            ));
+        }
+        let content = migration.get_content(direction)?.to_owned();
         self.runtime.block_on(async {
+            // Errors of individual statements are only reported by `check`
             self.client
-                .query(&migration)
+                .query(content)
                 .await
+                .and_then(|response| response.check())
+                .map_err(classify)
                 .chain_err(|| "Migration failed")
         })?;
 
-        self.set_current_number(number);
+        self.add_history_entry(&HistoryEntry::new(migration, direction, start.elapsed()))
+    }
+
+    fn in_transaction(&self, _: &Migration, _: Direction) -> bool {
+        false
+    }
+
+    fn lock(&mut self, timeout: Duration) -> Result<()> {
+        let lock_table = self.table.lock_table();
+        wait_for_lock(timeout, || {
+            self.runtime.block_on(async {
+                // Creating a record fails if it already exists
+                let query = format!("CREATE {}:lock SET locked_at = time::now();", lock_table);
+                let created = self.client.query(query).await.map_err(classify)?.check();
+                if created.is_ok() {
+                    return Ok(true);
+                }
+
+                let query = format!("SELECT VALUE true FROM ONLY {}:lock;", lock_table);
+                let mut result = self.client.query(query).await.map_err(classify)?;
+                let existing = result.take::<Option<bool>>(0).map_err(classify)?;
+                if existing.is_some() {
+                    Ok(false)
+                } else {
+                    created
+                        .map(|_| true)
+                        .chain_err(|| "Failed to acquire the migration lock")
+                }
+            })
+        })
+    }
+
+    fn unlock(&mut self) -> Result<()> {
+        let query = format!("DELETE {}:lock;", self.table.lock_table());
+        self.runtime.block_on(async {
+            self.client
+                .query(query)
+                .await
+                .and_then(|response| response.check())
+                .map_err(classify)?;
+            Ok(())
+        })
+    }
 
-        Ok(())
+    fn release_stale_lock(&mut self) -> Result<()> {
+        self.unlock()
     }
 }
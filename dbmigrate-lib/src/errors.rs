@@ -1,3 +1,5 @@
+#![allow(deprecated)]
+
 #[cfg(feature = "mysql_support")]
 use mysql_client;
 #[cfg(feature = "postgres_support")]
@@ -7,6 +9,8 @@ use sqlite_client;
 #[cfg(feature = "surreal_support")]
 use surreal_client;
 
+use crate::migrator::Report;
+
 error_chain! {
     foreign_links {
         Io(::std::io::Error) #[doc = "Failed to created/read migration files"];
@@ -15,4 +19,44 @@ error_chain! {
         Sqlite(sqlite_client::Error) #[doc = "Any Sqlite error"] #[cfg(feature = "sqlite_support")];
         Surreal(surreal_client::Error) #[doc = "Any SurrealDB error"] #[cfg(feature = "surreal_support")];
     }
+
+    errors {
+        /// The connection to the database was lost or could not be established
+        ConnectionLost {
+            description("connection to the database lost")
+            display("Lost the connection to the database")
+        }
+        /// The database user is not allowed to do what was asked
+        PermissionDenied {
+            description("permission denied")
+            display("The database denied the permission to run the query")
+        }
+        /// The migration table contains data dbmigrate doesn't understand
+        CorruptMigrationTable(reason: String) {
+            description("corrupt migration table")
+            display("The migration table is corrupt: {}", reason)
+        }
+        /// Some applied migrations had their file changed since they ran
+        ModifiedMigrations(numbers: Vec<u64>) {
+            description("applied migrations were modified")
+            display(
+                "Migrations modified after being applied: {}",
+                numbers.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")
+            )
+        }
+        /// A migration failed after the ones in the report ran, the error it failed
+        /// with being the cause
+        MigrationFailed(report: Report) {
+            description("a migration failed")
+            display("Failed after running {} migration(s)", report.migrations.len())
+        }
+        /// Some migrations older than the current one were not applied
+        OutOfOrderMigrations(numbers: Vec<u64>) {
+            description("migrations are out of order")
+            display(
+                "Migrations older than the current one were not applied: {}",
+                numbers.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")
+            )
+        }
+    }
 }
@@ -1,13 +1,19 @@
-use std::collections::BTreeMap;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::io::Write;
-use std::iter::repeat;
 use std::path::Path;
+use std::str::FromStr;
 
-use crate::errors::{Result, ResultExt};
+use crate::code::CodeMigration;
+use crate::errors::{Error, Result, ResultExt};
+use crate::history;
+use crate::placeholders::{Placeholders, substitute_placeholders};
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
 /// A migration direction, can be Up or Down
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -18,11 +24,23 @@ pub enum Direction {
     Down,
 }
 
-impl ToString for Direction {
-    fn to_string(&self) -> String {
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Direction::Up => "up".to_owned(),
-            Direction::Down => "down".to_owned(),
+            Direction::Up => write!(f, "up"),
+            Direction::Down => write!(f, "down"),
+        }
+    }
+}
+
+impl FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Direction> {
+        match s {
+            "up" => Ok(Direction::Up),
+            "down" => Ok(Direction::Down),
+            _ => bail!("Invalid migration direction: {}", s),
         }
     }
 }
@@ -35,28 +53,258 @@ pub struct Migration {
     /// Optional content of the down migration
     pub down: Option<String>,
     /// Migration number (must be positive)
-    pub number: u32,
+    pub number: u64,
     /// Migration name
     pub name: String,
+    /// Whether the up migration runs in a transaction, which is the case unless
+    /// the file header contains a `-- dbmigrate:no-transaction` comment
+    pub up_in_transaction: bool,
+    /// Whether the down migration runs in a transaction
+    pub down_in_transaction: bool,
+    /// How many digits the number takes in the filename, padded with zeroes
+    pub width: usize,
+    /// Whether this is a repeatable migration, ran again whenever its content
+    /// changes rather than once. Those have no down content and their number is 0.
+    pub repeatable: bool,
+    /// The Rust code to run instead of SQL content, for migrations written in Rust
+    pub code: Option<Box<dyn CodeMigration>>,
+    /// The driver the up file is specific to, if it is not the generic one
+    pub up_driver: Option<String>,
+    /// The driver the down file is specific to, if it is not the generic one
+    pub down_driver: Option<String>,
+    /// Checksum of the up content as it was in the file, set when its
+    /// placeholders are replaced
+    pub raw_checksum: Option<String>,
+}
+
+/// Width of the migration numbers in filenames when nothing else was asked
+pub const DEFAULT_WIDTH: usize = 4;
+
+/// Comment to put in the header of a migration file to run it outside of a transaction
+pub const NO_TRANSACTION_DIRECTIVE: &str = "dbmigrate:no-transaction";
+
+/// Looks for the no-transaction directive in the comments at the top of a file
+fn has_no_transaction_directive(content: &str) -> bool {
+    content
+        .lines()
+        .map(str::trim)
+        .take_while(|line| line.is_empty() || line.starts_with("--"))
+        .any(|line| line.trim_start_matches('-').trim() == NO_TRANSACTION_DIRECTIVE)
 }
 
 /// Simple way to hold migrations indexed by their number
-pub type Migrations = BTreeMap<u32, Migration>;
+pub type Migrations = BTreeMap<u64, Migration>;
+
+/// Repeatable migrations indexed by their name, which is also the order they run in
+pub type RepeatableMigrations = BTreeMap<String, Migration>;
+
+/// Timestamps numbers are written as `YYYYMMDDHHMMSS` so they always have 14 digits
+const TIMESTAMP_DIGITS: usize = 14;
+
+/// How migrations are numbered
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Numbering {
+    /// 1, 2, 3... without gaps
+    Sequential,
+    /// The UTC time the migration was created at, as `YYYYMMDDHHMMSS`.
+    /// Gaps are allowed so migrations created on different branches don't conflict
+    Timestamp,
+}
+
+impl Numbering {
+    /// Finds out the scheme used by existing migrations: as soon as there is
+    /// a timestamp, new migrations should use timestamps too
+    pub fn detect(migrations: &Migrations) -> Numbering {
+        if migrations.keys().any(|number| is_timestamp(*number)) {
+            Numbering::Timestamp
+        } else {
+            Numbering::Sequential
+        }
+    }
+
+    /// Gets the number to give to a new migration
+    pub fn next_number(&self, migrations: &Migrations) -> u64 {
+        let next = migrations.keys().next_back().map_or(1, |max| max + 1);
+        match *self {
+            Numbering::Sequential => next,
+            // Migrations created in the same second still need different numbers
+            Numbering::Timestamp => cmp::max(utc_timestamp(history::now()), next),
+        }
+    }
+}
+
+/// Whether a migration number is a timestamp rather than a sequential number
+fn is_timestamp(number: u64) -> bool {
+    number.to_string().len() == TIMESTAMP_DIGITS
+}
+
+/// Converts seconds since the UNIX epoch to a `YYYYMMDDHHMMSS` number
+fn utc_timestamp(secs: u64) -> u64 {
+    let days = (secs / 86_400) as i64;
+    let seconds_in_day = secs % 86_400;
+    // Converts days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = (year as u64) * 10_000 + (month as u64) * 100 + day as u64;
+    let time =
+        (seconds_in_day / 3600) * 10_000 + (seconds_in_day % 3600 / 60) * 100 + seconds_in_day % 60;
+    date * 1_000_000 + time
+}
 
 impl Migration {
     /// Creates a new migration
-    pub fn new(up: String, down: Option<String>, number: u32, name: String) -> Self {
+    pub fn new(up: String, down: Option<String>, number: u64, name: String) -> Self {
+        let up_in_transaction = !has_no_transaction_directive(&up);
+        let down_in_transaction = !down.as_deref().is_some_and(has_no_transaction_directive);
         Migration {
             up,
             down,
             number,
             name,
+            up_in_transaction,
+            down_in_transaction,
+            width: DEFAULT_WIDTH,
+            repeatable: false,
+            code: None,
+            up_driver: None,
+            down_driver: None,
+            raw_checksum: None,
+        }
+    }
+
+    /// Creates a new migration written in Rust, ran in a transaction on the
+    /// databases supporting it
+    pub fn new_code(number: u64, name: String, code: Box<dyn CodeMigration>) -> Migration {
+        Migration {
+            code: Some(code),
+            ..Migration::new(String::new(), None, number, name)
+        }
+    }
+
+    /// Creates a new repeatable migration
+    pub fn new_repeatable(content: String, name: String) -> Migration {
+        Migration {
+            repeatable: true,
+            ..Migration::new(content, None, 0, name)
+        }
+    }
+
+    /// Whether the given direction of the migration should run in a transaction
+    pub fn in_transaction(&self, direction: Direction) -> bool {
+        match direction {
+            Direction::Up => self.up_in_transaction,
+            Direction::Down => self.down_in_transaction,
+        }
+    }
+
+    /// The driver the file of the given direction is specific to, if any
+    pub fn driver(&self, direction: Direction) -> Option<&str> {
+        match direction {
+            Direction::Up => self.up_driver.as_deref(),
+            Direction::Down => self.down_driver.as_deref(),
+        }
+    }
+
+    /// Whether the migration can be reverted
+    pub fn has_down(&self) -> bool {
+        match self.code {
+            Some(ref code) => code.has_down(),
+            None => self.down.is_some(),
+        }
+    }
+
+    /// SHA-256 of the up content, recorded when the migration is ran
+    /// so we can detect files modified after being applied
+    pub fn checksum(&self) -> String {
+        if let Some(ref checksum) = self.raw_checksum {
+            return checksum.clone();
+        }
+        Sha256::digest(self.up.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Replaces the `${name}` placeholders of the up and down content by their value,
+    /// erroring if some have none.
+    /// The checksum stays the one of the file so it doesn't change with the values.
+    pub fn substitute_placeholders(&mut self, values: &Placeholders) -> Result<()> {
+        let checksum = self.checksum();
+        self.up = substitute_placeholders(&self.up, values)
+            .chain_err(|| format!("Invalid {}", self.get_filename(Direction::Up)))?;
+        if let Some(ref down) = self.down {
+            let down = substitute_placeholders(down, values)
+                .chain_err(|| format!("Invalid {}", self.get_filename(Direction::Down)))?;
+            self.down = Some(down);
+        }
+        self.raw_checksum = Some(checksum);
+        Ok(())
+    }
+
+    /// Same as `substitute_placeholders` on a copy of the migration, leaving this one
+    /// as it is. None for migrations written in Rust, which have no content.
+    pub fn with_placeholders(&self, values: &Placeholders) -> Result<Option<Migration>> {
+        if self.code.is_some() {
+            return Ok(None);
+        }
+        let mut migration = Migration {
+            up: self.up.clone(),
+            down: self.down.clone(),
+            number: self.number,
+            name: self.name.clone(),
+            up_in_transaction: self.up_in_transaction,
+            down_in_transaction: self.down_in_transaction,
+            width: self.width,
+            repeatable: self.repeatable,
+            code: None,
+            up_driver: self.up_driver.clone(),
+            down_driver: self.down_driver.clone(),
+            raw_checksum: self.raw_checksum.clone(),
+        };
+        migration.substitute_placeholders(values)?;
+        Ok(Some(migration))
+    }
+
+    /// Gets the content to run for a specific direction, erroring if
+    /// there is no down migration or if the migration is written in Rust
+    pub fn get_content(&self, direction: Direction) -> Result<&str> {
+        if self.code.is_some() {
+            bail!(
+                "Migration {} is written in Rust and has no content",
+                self.number
+            );
+        }
+        match direction {
+            Direction::Up => Ok(&self.up),
+            Direction::Down => match self.down {
+                Some(ref down) => Ok(down),
+                None => bail!("Migration {} has no down file", self.number),
+            },
         }
     }
 
     /// Gets the filename for a specific direction
     pub fn get_filename(&self, direction: Direction) -> String {
-        get_filename(&self.name, self.number, direction)
+        if self.repeatable {
+            return format!("R.{}.sql", self.name);
+        }
+        get_filename(
+            &self.name,
+            self.driver(direction),
+            self.number,
+            direction,
+            self.width,
+        )
     }
 
     /// Writes the migration files to disk
@@ -83,44 +331,109 @@ impl Migration {
     }
 }
 
-/// Creates a new migration with empty content
-pub fn create_migration(path: &Path, slug: &str, number: u32) -> Result<()> {
+/// Creates a new migration with empty content, its number padded to `width` digits,
+/// and returns it
+pub fn create_migration(path: &Path, slug: &str, number: u64, width: usize) -> Result<Migration> {
     let fixed_slug = slug.replace(" ", "_");
 
-    let migration = Migration::new(String::new(), Some(String::new()), number, fixed_slug);
+    let mut migration = Migration::new(String::new(), Some(String::new()), number, fixed_slug);
+    migration.width = width;
+    migration.write_to_disk(path)?;
 
-    println!("Creating {}", migration.get_filename(Direction::Up));
-    println!("Creating {}", migration.get_filename(Direction::Down));
+    Ok(migration)
+}
 
-    migration.write_to_disk(path)
+/// Get the filename to use for a migration using the given data, with the
+/// driver tag of the driver specific files
+fn get_filename(
+    slug: &str,
+    driver: Option<&str>,
+    number: u64,
+    direction: Direction,
+    width: usize,
+) -> String {
+    let tag = driver.map(|d| format!(".{}", d)).unwrap_or_default();
+    format!(
+        "{:0width$}.{}{}.{}.sql",
+        number,
+        slug,
+        tag,
+        direction,
+        width = width
+    )
 }
 
-/// Get the filename to use for a migration using the given data
-fn get_filename(slug: &str, number: u32, direction: Direction) -> String {
-    let num = number.to_string();
-    let filler = repeat("0").take(4 - num.len()).collect::<String>();
-    filler + &num + "." + slug + "." + &direction.to_string() + ".sql"
+/// Finds the width new migrations should use: the one of the latest sequential
+/// migration, or the default one
+pub fn detect_width(migrations: &Migrations) -> usize {
+    migrations
+        .values()
+        .rev()
+        .find(|m| !is_timestamp(m.number))
+        .map_or(DEFAULT_WIDTH, |m| m.width)
 }
 
 /// Information parsed from a migration filename
 struct FilenameInfo {
-    number: u32,
+    number: u64,
     name: String,
+    /// Set for the files specific to a driver
+    driver: Option<String>,
     direction: Direction,
+    width: usize,
 }
 
-/// Read the path given and read all the migration files, pairing them by migration
-/// number and checking for errors along the way
-pub fn read_migration_files(path: &Path) -> Result<Migrations> {
-    let mut migrations: Migrations = BTreeMap::new();
-    let mut up_files = BTreeMap::new();
-    let mut down_files = BTreeMap::new();
+/// Renames all the migration files of the directory so their numbers are padded
+/// to `width` digits. The numbers themselves don't change so the ones recorded
+/// in the database still match.
+/// Returns the list of `(old, new)` filenames.
+pub fn renumber_migration_files(path: &Path, width: usize) -> Result<Vec<(String, String)>> {
+    // Makes sure the directory is valid for every driver before touching anything
+    let drivers: BTreeSet<String> = read_files(path, parse_filename)?
+        .into_iter()
+        .filter_map(|(info, _)| info.driver)
+        .collect();
+    if drivers.is_empty() {
+        read_migration_files(path)?;
+    }
+    for driver in &drivers {
+        read_migration_files_for_driver(path, driver)?;
+    }
+
+    let mut renames = Vec::new();
+    for entry in fs::read_dir(path).chain_err(|| format!("Failed to open {:?}", path))? {
+        let filename = entry?.file_name().to_string_lossy().to_string();
+        if let Ok(info) = parse_filename(&filename) {
+            let new_filename = get_filename(
+                &info.name,
+                info.driver.as_deref(),
+                info.number,
+                info.direction,
+                width,
+            );
+            if new_filename != filename {
+                renames.push((filename, new_filename));
+            }
+        }
+    }
+    renames.sort();
+
+    for (old, new) in &renames {
+        fs::rename(path.join(old), path.join(new))
+            .chain_err(|| format!("Failed to rename {} to {}", old, new))?;
+    }
+    Ok(renames)
+}
 
+/// Reads the files of the directory whose name `parse` accepts, along with
+/// what it parsed from the name
+fn read_files<T>(path: &Path, parse: fn(&str) -> Result<T>) -> Result<Vec<(T, String)>> {
+    let mut files = Vec::new();
     for entry in fs::read_dir(path).chain_err(|| format!("Failed to open {:?}", path))? {
         let entry = entry.unwrap();
         let filename = entry.file_name().to_string_lossy().to_string();
 
-        let info = match parse_filename(&filename) {
+        let info = match parse(&filename) {
             Ok(info) => info,
             Err(_) => continue,
         };
@@ -129,31 +442,210 @@ pub fn read_migration_files(path: &Path) -> Result<Migrations> {
             File::open(entry.path()).chain_err(|| format!("Failed to open {:?}", entry.path()))?;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
+        files.push((info, content));
+    }
+    Ok(files)
+}
+
+/// Read the path given and read all the migration files, pairing them by migration
+/// number and checking for errors along the way.
+/// Files specific to a driver, like `0005.add_index.postgres.up.sql`, are ignored.
+pub fn read_migration_files(path: &Path) -> Result<Migrations> {
+    read_migration_files_with_code(path, None, Vec::new())
+}
+
+/// Same as `read_migration_files`, using the files specific to `driver`, the scheme
+/// of the database URL, instead of the generic ones when there are some
+pub fn read_migration_files_for_driver(path: &Path, driver: &str) -> Result<Migrations> {
+    read_migration_files_with_code(path, Some(driver), Vec::new())
+}
+
+/// Same as `read_migration_files_for_driver`, adding the given migrations written in Rust.
+/// Their numbers are checked along with the ones of the files so they can fill
+/// gaps, but they can't share a number with a file.
+pub fn read_migration_files_with_code(
+    path: &Path,
+    driver: Option<&str>,
+    code: Vec<Migration>,
+) -> Result<Migrations> {
+    let files = select_driver_files(read_files(path, parse_filename)?, driver)?;
+    pair_migration_files(files, code)
+}
+
+/// Reads the migration files of the path given whatever driver they are specific to,
+/// keeping one per number, so a new migration can be numbered after all of them.
+/// Unlike `read_migration_files` the files aren't checked nor paired.
+pub fn read_all_migration_files(path: &Path) -> Result<Migrations> {
+    let mut migrations = Migrations::new();
+    for (info, content) in read_files(path, parse_filename)? {
+        migrations.entry(info.number).or_insert_with(|| {
+            let mut migration = Migration::new(content, None, info.number, info.name);
+            migration.width = info.width;
+            migration
+        });
+    }
+    Ok(migrations)
+}
+
+/// Reads the repeatable migrations of the path given, named `R.<name>.sql`.
+/// They live next to the versioned ones, which `read_migration_files` reads.
+pub fn read_repeatable_migrations(path: &Path) -> Result<RepeatableMigrations> {
+    Ok(read_files(path, parse_repeatable_filename)?
+        .into_iter()
+        .map(|(name, content)| (name.clone(), Migration::new_repeatable(content, name)))
+        .collect())
+}
+
+/// Builds the migrations from files embedded in the binary, as `(filename, content)`
+/// pairs like the `embed_migrations!` macro gives, with the same rules as
+/// `read_migration_files`
+pub fn read_embedded_migrations(files: &[(&str, &str)]) -> Result<Migrations> {
+    read_embedded_migrations_with_code(files, None, Vec::new())
+}
 
+/// Same as `read_embedded_migrations`, using the files specific to `driver`
+/// like `read_migration_files_for_driver` does
+pub fn read_embedded_migrations_for_driver(
+    files: &[(&str, &str)],
+    driver: &str,
+) -> Result<Migrations> {
+    read_embedded_migrations_with_code(files, Some(driver), Vec::new())
+}
+
+/// Builds the repeatable migrations from files embedded in the binary like
+/// `read_repeatable_migrations` does, ignoring the versioned ones
+pub fn read_embedded_repeatable_migrations(files: &[(&str, &str)]) -> Result<RepeatableMigrations> {
+    Ok(files
+        .iter()
+        .filter_map(|(filename, content)| {
+            parse_repeatable_filename(filename).ok().map(|name| {
+                let migration = Migration::new_repeatable(content.to_string(), name.clone());
+                (name, migration)
+            })
+        })
+        .collect())
+}
+
+/// Same as `read_embedded_migrations`, picking the files specific to `driver` and
+/// adding the given migrations written in Rust like `read_migration_files_with_code` does
+pub fn read_embedded_migrations_with_code(
+    files: &[(&str, &str)],
+    driver: Option<&str>,
+    code: Vec<Migration>,
+) -> Result<Migrations> {
+    let files = files
+        .iter()
+        .filter_map(|(filename, content)| {
+            parse_filename(filename)
+                .ok()
+                .map(|info| (info, content.to_string()))
+        })
+        .collect();
+
+    pair_migration_files(select_driver_files(files, driver)?, code)
+}
+
+/// Keeps the files specific to `driver` and the generic files that don't have one,
+/// erroring if a migration has no up file left while other drivers have one
+fn select_driver_files(
+    files: Vec<(FilenameInfo, String)>,
+    driver: Option<&str>,
+) -> Result<Vec<(FilenameInfo, String)>> {
+    let is_ours = |info: &FilenameInfo| info.driver.is_some() && info.driver.as_deref() == driver;
+    let overridden: BTreeSet<(u64, bool)> = files
+        .iter()
+        .filter(|(info, _)| is_ours(info))
+        .map(|(info, _)| (info.number, info.direction == Direction::Up))
+        .collect();
+
+    let mut selected = Vec::new();
+    let mut others: BTreeMap<u64, Vec<String>> = BTreeMap::new();
+    for (info, content) in files {
+        let key = (info.number, info.direction == Direction::Up);
+        match info.driver {
+            None if !overridden.contains(&key) => selected.push((info, content)),
+            Some(_) if is_ours(&info) => selected.push((info, content)),
+            Some(ref other) if info.direction == Direction::Up => {
+                others.entry(info.number).or_default().push(other.clone());
+            }
+            // Generic files replaced by ours and down files of other drivers
+            _ => (),
+        }
+    }
+
+    for (number, drivers) in others {
+        let has_up = selected
+            .iter()
+            .any(|(info, _)| info.number == number && info.direction == Direction::Up);
+        if !has_up {
+            bail!(
+                "Migration {} only has files specific to {}",
+                number,
+                drivers.join(", ")
+            );
+        }
+    }
+    Ok(selected)
+}
+
+/// Pairs the up and down files by migration number and adds the code migrations,
+/// checking for errors along the way
+fn pair_migration_files(
+    files: Vec<(FilenameInfo, String)>,
+    code: Vec<Migration>,
+) -> Result<Migrations> {
+    let mut migrations: Migrations = BTreeMap::new();
+    let mut up_files = BTreeMap::new();
+    let mut down_files = BTreeMap::new();
+
+    for (info, content) in files {
         match info.direction {
             Direction::Up => {
-                up_files.insert(info.number, (info.name, content));
+                let up = (info.name, info.driver, info.width, content);
+                let previous = up_files.insert(info.number, up);
+                if previous.is_some() {
+                    bail!("Found several up files for migration {}", info.number);
+                }
             }
             Direction::Down => {
-                down_files.insert(info.number, content);
+                if down_files
+                    .insert(info.number, (info.driver, content))
+                    .is_some()
+                {
+                    bail!("Found several down files for migration {}", info.number);
+                }
             }
         }
     }
 
-    for (number, (name, up_content)) in up_files {
-        let down_content = down_files.remove(&number);
+    for (number, (name, up_driver, width, up_content)) in up_files {
+        let (down_driver, down_content) = match down_files.remove(&number) {
+            Some((driver, content)) => (driver, Some(content)),
+            None => (None, None),
+        };
 
-        let migration = Migration::new(up_content, down_content, number, name);
+        let mut migration = Migration::new(up_content, down_content, number, name);
+        migration.width = width;
+        migration.up_driver = up_driver;
+        migration.down_driver = down_driver;
 
         migrations.insert(number, migration);
     }
 
-    let mut expected_number = 1;
-    for number in migrations.keys() {
+    for migration in code {
+        let number = migration.number;
+        if migrations.insert(number, migration).is_some() {
+            bail!("Migration {} is both a file and written in Rust", number);
+        }
+    }
+
+    // Only sequential numbers need to be contiguous, timestamps can have gaps
+    let sequential = migrations.keys().filter(|number| !is_timestamp(**number));
+    for (index, number) in sequential.enumerate() {
+        let expected_number = index as u64 + 1;
         if *number != expected_number {
             bail!("Files for migration {} are missing", expected_number);
         }
-        expected_number += 1;
     }
 
     if !down_files.is_empty() {
@@ -170,9 +662,10 @@ pub fn read_migration_files(path: &Path) -> Result<Migrations> {
 /// Gets a filename and check whether it's a valid format.
 /// If it is, grabs all the info from it
 fn parse_filename(filename: &str) -> Result<FilenameInfo> {
-    let re =
-        Regex::new(r"^(?P<number>[0-9]{4})\.(?P<name>[_0-9a-zA-Z]*)\.(?P<direction>up|down)\.sql$")
-            .unwrap();
+    let re = Regex::new(
+        r"^(?P<number>[0-9]+)\.(?P<name>[_0-9a-zA-Z]*)(\.(?P<driver>[a-z]+))?\.(?P<direction>up|down)\.sql$",
+    )
+    .unwrap();
 
     let caps = match re.captures(filename) {
         None => bail!("File {} has an invalid filename", filename),
@@ -180,13 +673,12 @@ fn parse_filename(filename: &str) -> Result<FilenameInfo> {
     };
 
     // Unwrapping below should be safe (in theory)
-    let number = caps
-        .name("number")
-        .unwrap()
-        .as_str()
-        .parse::<u32>()
-        .unwrap();
+    let digits = caps.name("number").unwrap().as_str();
+    let number = digits
+        .parse::<u64>()
+        .chain_err(|| format!("File {} has a number that is too large", filename))?;
     let name = caps.name("name").unwrap().as_str().to_string();
+    let driver = caps.name("driver").map(|d| d.as_str().to_string());
     let direction = if caps.name("direction").unwrap().as_str() == "up" {
         Direction::Up
     } else {
@@ -196,18 +688,64 @@ fn parse_filename(filename: &str) -> Result<FilenameInfo> {
     Ok(FilenameInfo {
         number,
         name,
+        driver,
         direction,
+        width: digits.len(),
     })
 }
 
+/// Gets the name of a repeatable migration from its filename, erroring if it
+/// isn't one
+fn parse_repeatable_filename(filename: &str) -> Result<String> {
+    let re = Regex::new(r"^R\.(?P<name>[_0-9a-zA-Z]+)\.sql$").unwrap();
+    match re.captures(filename) {
+        Some(caps) => Ok(caps.name("name").unwrap().as_str().to_string()),
+        None => bail!("File {} is not a repeatable migration", filename),
+    }
+}
+
+/// Migrations shared by the tests of the other modules
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{Migration, Migrations};
+
+    /// A migration creating `table_<number>` and dropping it when reverted
+    pub fn migration(number: u64) -> Migration {
+        Migration::new(
+            format!("CREATE TABLE table_{} (id INTEGER);", number),
+            Some(format!("DROP TABLE table_{};", number)),
+            number,
+            format!("table_{}", number),
+        )
+    }
+
+    /// Migrations 1 to `count`, as made by `migration`
+    pub fn migrations(count: u64) -> Migrations {
+        (1..=count)
+            .map(|number| (number, migration(number)))
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Direction, Migration, get_filename, parse_filename, read_migration_files};
+    use super::{
+        Direction, Migration, Migrations, Numbering, detect_width, get_filename,
+        has_no_transaction_directive, parse_filename, read_embedded_migrations,
+        read_embedded_migrations_for_driver, read_embedded_repeatable_migrations,
+        read_migration_files, read_migration_files_for_driver, read_migration_files_with_code,
+        read_repeatable_migrations, renumber_migration_files, utc_timestamp,
+    };
+    use crate::code::{CodeMigration, Connection};
+    use crate::embed_migrations;
+    use crate::errors::Result;
+    use crate::placeholders::Placeholders;
     use std::fs::File;
     use std::io::prelude::*;
     use std::path::PathBuf;
     use tempdir::TempDir;
 
+    #[allow(clippy::ptr_arg)]
     fn create_file(path: &PathBuf, filename: &str, content: &str) {
         let mut new_path = path.clone();
         new_path.push(filename);
@@ -224,16 +762,81 @@ mod tests {
     }
 
     #[test]
+    fn test_parse_driver_filename() {
+        let result = parse_filename("0005.add_index.postgres.down.sql").unwrap();
+        assert_eq!(result.number, 5);
+        assert_eq!(result.name, "add_index");
+        assert_eq!(result.driver, Some("postgres".to_string()));
+        assert_eq!(result.direction, Direction::Down);
+        assert_eq!(
+            parse_filename("0005.add_index.up.sql").unwrap().driver,
+            None
+        );
+        assert_eq!(
+            get_filename("add_index", Some("sqlite"), 5, Direction::Up, 4),
+            "0005.add_index.sqlite.up.sql"
+        );
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
     fn test_parse_bad_filename_format() {
         // Has _ instead of . between number and name
         let result = parse_filename("0001_tests.up.sql");
         assert_eq!(result.is_ok(), false);
     }
 
+    #[test]
+    fn test_parse_timestamp_filename() {
+        let result = parse_filename("20261018123000.add_users.up.sql").unwrap();
+        assert_eq!(result.number, 20261018123000);
+        assert_eq!(result.name, "add_users");
+        assert_eq!(
+            get_filename("add_users", None, 20261018123000, Direction::Up, 4),
+            "20261018123000.add_users.up.sql"
+        );
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(0), 19700101000000);
+        assert_eq!(utc_timestamp(1_700_000_000), 20231114221320);
+        assert_eq!(utc_timestamp(951_782_400), 20000229000000);
+    }
+
+    #[test]
+    fn test_numbering() {
+        let mut migrations = Migrations::new();
+        assert_eq!(Numbering::detect(&migrations), Numbering::Sequential);
+        assert_eq!(Numbering::Sequential.next_number(&migrations), 1);
+
+        migrations.insert(1, Migration::new(String::new(), None, 1, "a".to_string()));
+        assert_eq!(Numbering::detect(&migrations), Numbering::Sequential);
+        assert_eq!(Numbering::Sequential.next_number(&migrations), 2);
+        assert!(Numbering::Timestamp.next_number(&migrations) > 20260000000000);
+
+        // A migration created in the future still comes before the next one
+        let future = 29991231235959;
+        migrations.insert(
+            future,
+            Migration::new(String::new(), None, future, "b".to_string()),
+        );
+        assert_eq!(Numbering::detect(&migrations), Numbering::Timestamp);
+        assert_eq!(Numbering::Timestamp.next_number(&migrations), future + 1);
+    }
+
     #[test]
     fn test_get_filename_ok() {
-        let result = get_filename("initial", 1, Direction::Up);
+        let result = get_filename("initial", None, 1, Direction::Up, 4);
         assert_eq!(result, "0001.initial.up.sql");
+        assert_eq!(
+            get_filename("initial", None, 10000, Direction::Up, 4),
+            "10000.initial.up.sql"
+        );
+        assert_eq!(
+            get_filename("initial", None, 1, Direction::Up, 6),
+            "000001.initial.up.sql"
+        );
     }
 
     #[test]
@@ -255,6 +858,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_no_transaction_directive() {
+        assert!(has_no_transaction_directive(
+            "-- dbmigrate:no-transaction\nCREATE INDEX CONCURRENTLY idx ON users (name);"
+        ));
+        assert!(has_no_transaction_directive(
+            "\n-- Needs to run outside of a transaction\n--dbmigrate:no-transaction\nVACUUM;"
+        ));
+        // Only the header is looked at
+        assert!(!has_no_transaction_directive(
+            "CREATE TABLE users;\n-- dbmigrate:no-transaction"
+        ));
+
+        let migration = Migration::new(
+            "-- dbmigrate:no-transaction\nVACUUM;".to_string(),
+            Some("SELECT 1;".to_string()),
+            1,
+            "vacuum".to_string(),
+        );
+        assert!(!migration.in_transaction(Direction::Up));
+        assert!(migration.in_transaction(Direction::Down));
+    }
+
+    #[test]
+    fn test_migration_checksum() {
+        let migration = Migration::new("CREATE TABLE users;".to_string(), None, 1, "a".to_string());
+        let same = Migration::new("CREATE TABLE users;".to_string(), None, 1, "b".to_string());
+        let modified = Migration::new("CREATE TABLE user;".to_string(), None, 1, "a".to_string());
+
+        assert_eq!(migration.checksum().len(), 64);
+        assert_eq!(migration.checksum(), same.checksum());
+        assert_ne!(migration.checksum(), modified.checksum());
+    }
+
     #[test]
     fn test_parse_good_migrations_directory() {
         let pathbuf = TempDir::new("migrations").unwrap().into_path();
@@ -280,6 +917,111 @@ mod tests {
         assert_eq!(second.down, Some("-- Revert ALTER".to_string()));
     }
 
+    #[test]
+    fn test_parse_repeatable_migrations() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql", "CREATE TABLE tests;");
+        create_file(
+            &pathbuf,
+            "R.refresh_views.sql",
+            "CREATE VIEW v AS SELECT 1;",
+        );
+        create_file(&pathbuf, "R.grants.sql", "GRANT SELECT ON tests TO app;");
+        create_file(&pathbuf, "R..sql", "-- Not a migration");
+
+        // Each kind of migration is only read by its own function
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        assert_eq!(migrations.len(), 1);
+
+        let repeatables = read_repeatable_migrations(pathbuf.as_path()).unwrap();
+        let names: Vec<&str> = repeatables.keys().map(|n| n.as_str()).collect();
+        assert_eq!(names, vec!["grants", "refresh_views"]);
+        let refresh = &repeatables["refresh_views"];
+        assert!(refresh.repeatable);
+        assert_eq!(refresh.number, 0);
+        assert_eq!(refresh.up, "CREATE VIEW v AS SELECT 1;");
+        assert_eq!(refresh.get_filename(Direction::Up), "R.refresh_views.sql");
+    }
+
+    struct Noop;
+
+    impl CodeMigration for Noop {
+        fn up(&self, _: Connection) -> Result<()> {
+            Ok(())
+        }
+        fn down(&self, _: Connection) -> Result<()> {
+            Ok(())
+        }
+        fn has_down(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_read_migration_files_with_code() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql", "CREATE TABLE tests;");
+        create_file(&pathbuf, "0003.more.up.sql", "CREATE TABLE more;");
+
+        let code = vec![Migration::new_code(
+            2,
+            "backfill".to_string(),
+            Box::new(Noop),
+        )];
+        let migrations = read_migration_files_with_code(pathbuf.as_path(), None, code).unwrap();
+        let numbers: Vec<u64> = migrations.keys().cloned().collect();
+        assert_eq!(numbers, vec![1, 2, 3]);
+        let backfill = &migrations[&2];
+        assert_eq!(backfill.name, "backfill");
+        assert!(!backfill.has_down());
+        assert!(backfill.get_content(Direction::Up).is_err());
+
+        let code = vec![Migration::new_code(3, "taken".to_string(), Box::new(Noop))];
+        assert!(read_migration_files_with_code(pathbuf.as_path(), None, code).is_err());
+    }
+
+    #[test]
+    fn test_driver_variants() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.users.up.sql", "CREATE TABLE users;");
+        create_file(&pathbuf, "0001.users.down.sql", "DROP TABLE users;");
+        create_file(
+            &pathbuf,
+            "0001.users.postgres.up.sql",
+            "CREATE TABLE users ();",
+        );
+        create_file(
+            &pathbuf,
+            "0002.index.postgres.up.sql",
+            "CREATE INDEX CONCURRENTLY;",
+        );
+        create_file(&pathbuf, "0002.index.sqlite.up.sql", "CREATE INDEX;");
+
+        let migrations = read_migration_files_for_driver(pathbuf.as_path(), "postgres").unwrap();
+        let users = &migrations[&1];
+        assert_eq!(users.up, "CREATE TABLE users ();");
+        assert_eq!(
+            users.get_filename(Direction::Up),
+            "0001.users.postgres.up.sql"
+        );
+        // Falls back to the generic file
+        assert_eq!(users.down, Some("DROP TABLE users;".to_string()));
+        assert_eq!(users.get_filename(Direction::Down), "0001.users.down.sql");
+        assert_eq!(migrations[&2].up, "CREATE INDEX CONCURRENTLY;");
+
+        let migrations = read_migration_files_for_driver(pathbuf.as_path(), "sqlite").unwrap();
+        assert_eq!(migrations[&1].up, "CREATE TABLE users;");
+        assert_eq!(migrations[&2].up, "CREATE INDEX;");
+
+        // Neither a generic file nor one for that driver
+        assert!(read_migration_files_for_driver(pathbuf.as_path(), "mysql").is_err());
+        assert!(read_migration_files(pathbuf.as_path()).is_err());
+
+        // Renumbering keeps the tags
+        renumber_migration_files(pathbuf.as_path(), 5).unwrap();
+        assert!(pathbuf.join("00002.index.sqlite.up.sql").exists());
+    }
+
     #[test]
     fn test_migration_with_only_up() {
         let pathbuf = TempDir::new("migrations").unwrap().into_path();
@@ -307,6 +1049,111 @@ mod tests {
         assert!(migrations.is_err());
     }
 
+    #[test]
+    fn test_parse_timestamp_migrations_directory() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql", "CREATE TABLE tests;");
+        create_file(
+            &pathbuf,
+            "20261018123000.users.up.sql",
+            "CREATE TABLE users;",
+        );
+        create_file(
+            &pathbuf,
+            "20261020090000.posts.up.sql",
+            "CREATE TABLE posts;",
+        );
+
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        let numbers: Vec<u64> = migrations.keys().cloned().collect();
+        assert_eq!(numbers, vec![1, 20261018123000, 20261020090000]);
+    }
+
+    #[test]
+    fn test_parse_variable_width_migrations_directory() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql", "CREATE TABLE tests;");
+        create_file(&pathbuf, "00002.more.up.sql", "CREATE TABLE more;");
+        create_file(&pathbuf, "00002.more.down.sql", "DROP TABLE more;");
+
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(
+            migrations[&2].get_filename(Direction::Down),
+            "00002.more.down.sql"
+        );
+        assert_eq!(detect_width(&migrations), 5);
+
+        // The same number can't be used twice with different widths
+        create_file(&pathbuf, "002.duplicate.up.sql", "CREATE TABLE duplicate;");
+        assert!(read_migration_files(pathbuf.as_path()).is_err());
+    }
+
+    #[test]
+    fn test_renumber_migration_files() {
+        let pathbuf = TempDir::new("migrations").unwrap().into_path();
+        create_file(&pathbuf, "0001.tests.up.sql", "CREATE TABLE tests;");
+        create_file(&pathbuf, "0001.tests.down.sql", "DROP TABLE tests;");
+        create_file(&pathbuf, "0002.more.up.sql", "CREATE TABLE more;");
+
+        let renames = renumber_migration_files(pathbuf.as_path(), 6).unwrap();
+        assert_eq!(renames.len(), 3);
+
+        let migrations = read_migration_files(pathbuf.as_path()).unwrap();
+        let numbers: Vec<u64> = migrations.keys().cloned().collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert_eq!(
+            migrations[&1].get_filename(Direction::Up),
+            "000001.tests.up.sql"
+        );
+        assert!(pathbuf.join("000001.tests.down.sql").exists());
+        assert!(
+            renumber_migration_files(pathbuf.as_path(), 6)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_read_embedded_migrations() {
+        let migrations = read_embedded_migrations(&[
+            ("0001.tests.up.sql", "CREATE TABLE tests;"),
+            ("0001.tests.down.sql", "DROP TABLE tests;"),
+            ("0002.more.up.sql", "CREATE TABLE more;"),
+            ("notes.sql", "-- Not a migration"),
+        ])
+        .unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[&1].down, Some("DROP TABLE tests;".to_string()));
+
+        // Same validation as when reading a directory
+        assert!(read_embedded_migrations(&[("0002.more.up.sql", "CREATE TABLE more;")]).is_err());
+    }
+
+    #[test]
+    fn test_embed_migrations_macro() {
+        let files: &[(&str, &str)] = embed_migrations!("test-migrations");
+        // Any string literal works
+        assert_eq!(embed_migrations!(r"test-migrations"), files);
+        let migrations = read_embedded_migrations(files).unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert_eq!(migrations[&1].name, "users");
+        assert_eq!(migrations[&1].up, "CREATE TABLE users (id INTEGER);\n");
+        assert!(!migrations[&2].in_transaction(Direction::Up));
+
+        let migrations = read_embedded_migrations_for_driver(files, "sqlite").unwrap();
+        assert_eq!(
+            migrations[&1].up,
+            "CREATE TABLE users (id INTEGER) STRICT;\n"
+        );
+        assert_eq!(migrations[&1].up_driver.as_deref(), Some("sqlite"));
+        assert_eq!(migrations[&1].down_driver, None);
+
+        let repeatables = read_embedded_repeatable_migrations(files).unwrap();
+        assert_eq!(repeatables.len(), 1);
+        assert!(repeatables["active_users"].repeatable);
+    }
+
     #[test]
     fn test_orphaned_down_migration() {
         let pathbuf = TempDir::new("migrations").unwrap().into_path();
@@ -342,4 +1189,22 @@ mod tests {
         down_file.read_to_string(&mut down_content).unwrap();
         assert_eq!(down_content, "DROP TABLE users;");
     }
+
+    #[test]
+    fn test_substitute_placeholders_keeps_checksum() {
+        let mut migration = Migration::new(
+            "CREATE TABLE ${schema}.users;".to_string(),
+            Some("DROP TABLE ${schema}.users;".to_string()),
+            1,
+            "create_users".to_string(),
+        );
+        let checksum = migration.checksum();
+
+        let mut values = Placeholders::new();
+        values.insert("schema".to_string(), "billing".to_string());
+        migration.substitute_placeholders(&values).unwrap();
+        assert_eq!(migration.up, "CREATE TABLE billing.users;");
+        assert_eq!(migration.down.as_deref(), Some("DROP TABLE billing.users;"));
+        assert_eq!(migration.checksum(), checksum);
+    }
 }
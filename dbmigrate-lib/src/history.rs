@@ -0,0 +1,475 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::errors::{Error, ErrorKind, Result};
+use crate::files::{Direction, Migration, Migrations, RepeatableMigrations};
+
+/// How a history entry came to be recorded
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EntryKind {
+    /// The migration was ran
+    Ran,
+    /// The migration was recorded as applied by `baseline` without running it,
+    /// as the database already matched it
+    Baseline,
+    /// The migration was recorded as applied or reverted by `force` without
+    /// running it, to repair the history
+    Forced,
+    /// The migration started running: it is followed by another entry for the same
+    /// migration once it is done, unless the process died or it failed midway
+    Started,
+    /// A repeatable migration was ran. It is not part of the versioned migrations:
+    /// its number is 0 and it is identified by its name
+    Repeatable,
+}
+
+impl EntryKind {
+    /// Whether the entry changes which versioned migrations are applied
+    fn is_versioned(self) -> bool {
+        !matches!(self, EntryKind::Started | EntryKind::Repeatable)
+    }
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EntryKind::Ran => write!(f, "ran"),
+            EntryKind::Baseline => write!(f, "baseline"),
+            EntryKind::Forced => write!(f, "forced"),
+            EntryKind::Started => write!(f, "started"),
+            EntryKind::Repeatable => write!(f, "repeatable"),
+        }
+    }
+}
+
+impl FromStr for EntryKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EntryKind> {
+        match s {
+            "ran" => Ok(EntryKind::Ran),
+            "baseline" => Ok(EntryKind::Baseline),
+            "forced" => Ok(EntryKind::Forced),
+            "started" => Ok(EntryKind::Started),
+            "repeatable" => Ok(EntryKind::Repeatable),
+            _ => bail!("Invalid history entry kind: {}", s),
+        }
+    }
+}
+
+/// A row of the migration history table: every time a migration is ran
+/// in either direction, a new entry is added, after one marking it as started
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// Number of the migration that was ran
+    pub number: u64,
+    /// Name of the migration that was ran
+    pub name: String,
+    /// When the migration was applied, in seconds since the UNIX epoch
+    pub applied_at: u64,
+    /// How long the migration took to run, in milliseconds
+    pub duration_ms: u64,
+    /// Checksum of the migration content, if known
+    pub checksum: Option<String>,
+    /// In which direction the migration was ran
+    pub direction: Direction,
+    /// Whether the migration was actually ran
+    pub kind: EntryKind,
+}
+
+impl HistoryEntry {
+    /// Creates the entry for a migration that was just ran
+    pub fn new(migration: &Migration, direction: Direction, duration: Duration) -> HistoryEntry {
+        HistoryEntry {
+            number: migration.number,
+            name: migration.name.clone(),
+            applied_at: now(),
+            duration_ms: duration.as_millis() as u64,
+            checksum: Some(migration.checksum()),
+            direction,
+            kind: if migration.repeatable {
+                EntryKind::Repeatable
+            } else {
+                EntryKind::Ran
+            },
+        }
+    }
+
+    /// Creates the entry recording a migration as applied without running it
+    pub fn baseline(migration: &Migration) -> HistoryEntry {
+        HistoryEntry {
+            kind: EntryKind::Baseline,
+            ..HistoryEntry::new(migration, Direction::Up, Duration::from_secs(0))
+        }
+    }
+
+    /// Creates the entry marking a migration as running, before running it
+    pub fn started(migration: &Migration, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            kind: EntryKind::Started,
+            ..HistoryEntry::new(migration, direction, Duration::from_secs(0))
+        }
+    }
+
+    /// Creates the entry recording a migration as applied or reverted without
+    /// running it. Migrations whose files are gone only have a number and a name.
+    pub fn forced(number: u64, name: &str, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            number,
+            name: name.to_string(),
+            applied_at: now(),
+            duration_ms: 0,
+            checksum: None,
+            direction,
+            kind: EntryKind::Forced,
+        }
+    }
+}
+
+/// Builds an entry from the values of a migration table row, erroring if
+/// they don't make sense.
+/// Rows added before kinds were recorded have none and were all ran.
+pub(crate) fn entry_from_row(
+    number: i64,
+    name: String,
+    applied_at: i64,
+    duration_ms: i64,
+    checksum: Option<String>,
+    direction: &str,
+    kind: Option<&str>,
+) -> Result<HistoryEntry> {
+    let corrupt = |field: &str, value: &dyn fmt::Display| {
+        ErrorKind::CorruptMigrationTable(format!("invalid {} `{}` for {}", field, value, name))
+    };
+    Ok(HistoryEntry {
+        number: u64::try_from(number).map_err(|_| corrupt("number", &number))?,
+        applied_at: u64::try_from(applied_at).map_err(|_| corrupt("applied_at", &applied_at))?,
+        duration_ms: u64::try_from(duration_ms)
+            .map_err(|_| corrupt("duration_ms", &duration_ms))?,
+        checksum,
+        direction: direction
+            .parse()
+            .map_err(|_| corrupt("direction", &direction))?,
+        kind: match kind {
+            Some(kind) => kind.parse().map_err(|_| corrupt("kind", &kind))?,
+            None => EntryKind::Ran,
+        },
+        name,
+    })
+}
+
+/// Seconds elapsed since the UNIX epoch
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Builds the history entries replacing the single `current` row
+/// that older versions of dbmigrate stored in the migration table.
+/// Names and durations were never recorded so they are left empty.
+pub(crate) fn legacy_entries(current: u64) -> Vec<HistoryEntry> {
+    let applied_at = now();
+    (1..=current)
+        .map(|number| HistoryEntry {
+            number,
+            name: String::new(),
+            applied_at,
+            duration_ms: 0,
+            checksum: None,
+            direction: Direction::Up,
+            kind: EntryKind::Ran,
+        })
+        .collect()
+}
+
+/// Replays the history (oldest entry first) to find which migrations
+/// are currently applied
+pub fn applied_numbers(history: &[HistoryEntry]) -> BTreeSet<u64> {
+    let mut applied = BTreeSet::new();
+    for entry in history.iter().filter(|e| e.kind.is_versioned()) {
+        match entry.direction {
+            Direction::Up => {
+                applied.insert(entry.number);
+            }
+            Direction::Down => {
+                applied.remove(&entry.number);
+            }
+        }
+    }
+    applied
+}
+
+/// The current migration number is the highest applied one, 0 if
+/// nothing has been applied yet
+pub fn current_number(history: &[HistoryEntry]) -> u64 {
+    applied_numbers(history).last().cloned().unwrap_or(0)
+}
+
+/// Finds the applied migrations whose file content no longer matches the
+/// checksum recorded when they were applied.
+/// Entries without a checksum, like the ones upgraded from a legacy table, are skipped.
+pub fn find_modified_migrations(migrations: &Migrations, history: &[HistoryEntry]) -> Vec<u64> {
+    let mut checksums = BTreeMap::new();
+    for entry in history {
+        if entry.direction == Direction::Up && entry.kind.is_versioned() {
+            checksums.insert(entry.number, &entry.checksum);
+        }
+    }
+
+    applied_numbers(history)
+        .into_iter()
+        .filter(
+            |number| match (migrations.get(number), checksums.get(number)) {
+                (Some(migration), Some(Some(checksum))) => migration.checksum() != **checksum,
+                _ => false,
+            },
+        )
+        .collect()
+}
+
+/// Finds the migrations that started running but never finished, because the
+/// process died or because they failed: the database might be partially migrated
+pub fn find_dirty_migrations(history: &[HistoryEntry]) -> Vec<u64> {
+    let mut last_kinds = BTreeMap::new();
+    for entry in history.iter().filter(|e| e.kind != EntryKind::Repeatable) {
+        last_kinds.insert(entry.number, entry.kind);
+    }
+    last_kinds
+        .into_iter()
+        .filter(|(_, kind)| *kind == EntryKind::Started)
+        .map(|(number, _)| number)
+        .collect()
+}
+
+/// Finds the names of the repeatable migrations that need to run: the ones never
+/// ran and the ones whose content changed since they last ran
+pub fn find_pending_repeatables(
+    repeatables: &RepeatableMigrations,
+    history: &[HistoryEntry],
+) -> Vec<String> {
+    let mut checksums = BTreeMap::new();
+    for entry in history.iter().filter(|e| e.kind == EntryKind::Repeatable) {
+        checksums.insert(entry.name.as_str(), &entry.checksum);
+    }
+
+    repeatables
+        .values()
+        .filter(|migration| match checksums.get(migration.name.as_str()) {
+            Some(Some(checksum)) => migration.checksum() != **checksum,
+            _ => true,
+        })
+        .map(|migration| migration.name.clone())
+        .collect()
+}
+
+/// Errors with the list of modified migrations if any applied migration
+/// file was changed after being applied
+pub fn verify_checksums(migrations: &Migrations, history: &[HistoryEntry]) -> Result<()> {
+    let modified = find_modified_migrations(migrations, history);
+    if !modified.is_empty() {
+        bail!(ErrorKind::ModifiedMigrations(modified));
+    }
+    Ok(())
+}
+
+/// Finds the migrations that were not applied even though a newer one was,
+/// typically because they were merged from a branch created before it
+pub fn find_out_of_order_migrations(migrations: &Migrations, history: &[HistoryEntry]) -> Vec<u64> {
+    let applied = applied_numbers(history);
+    let current = current_number(history);
+    migrations
+        .range(..current)
+        .map(|(number, _)| *number)
+        .filter(|number| !applied.contains(number))
+        .collect()
+}
+
+/// Errors with the list of out of order migrations if there are any
+pub fn verify_order(migrations: &Migrations, history: &[HistoryEntry]) -> Result<()> {
+    let out_of_order = find_out_of_order_migrations(migrations, history);
+    if !out_of_order.is_empty() {
+        bail!(ErrorKind::OutOfOrderMigrations(out_of_order));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        EntryKind, HistoryEntry, applied_numbers, current_number, entry_from_row,
+        find_dirty_migrations, find_modified_migrations, find_out_of_order_migrations,
+        find_pending_repeatables, legacy_entries,
+    };
+    use crate::files::fixtures::migrations;
+    use crate::files::{Direction, Migration, Migrations, RepeatableMigrations};
+    use std::time::Duration;
+
+    fn entry(number: u64, direction: Direction) -> HistoryEntry {
+        HistoryEntry {
+            number,
+            name: format!("migration_{}", number),
+            applied_at: 0,
+            duration_ms: 0,
+            checksum: None,
+            direction,
+            kind: EntryKind::Ran,
+        }
+    }
+
+    #[test]
+    fn test_empty_history() {
+        assert!(applied_numbers(&[]).is_empty());
+        assert_eq!(current_number(&[]), 0);
+    }
+
+    #[test]
+    fn test_replay_history() {
+        let history = vec![
+            entry(1, Direction::Up),
+            entry(2, Direction::Up),
+            entry(3, Direction::Up),
+            entry(3, Direction::Down),
+            entry(2, Direction::Down),
+            entry(2, Direction::Up),
+        ];
+        let applied: Vec<u64> = applied_numbers(&history).into_iter().collect();
+        assert_eq!(applied, vec![1, 2]);
+        assert_eq!(current_number(&history), 2);
+    }
+
+    #[test]
+    fn test_dirty_migrations() {
+        let started = |number, direction| HistoryEntry {
+            kind: EntryKind::Started,
+            ..entry(number, direction)
+        };
+        let mut history = vec![
+            started(1, Direction::Up),
+            entry(1, Direction::Up),
+            started(2, Direction::Up),
+        ];
+        assert!(find_dirty_migrations(&history[..2]).is_empty());
+        assert_eq!(find_dirty_migrations(&history), vec![2]);
+        // Started entries don't count as applied
+        assert_eq!(current_number(&history), 1);
+
+        history.push(HistoryEntry::forced(2, "migration_2", Direction::Up));
+        assert!(find_dirty_migrations(&history).is_empty());
+        assert_eq!(current_number(&history), 2);
+    }
+
+    #[test]
+    fn test_entry_from_row() {
+        let entry = entry_from_row(
+            3,
+            "users".to_string(),
+            1_700_000_000,
+            12,
+            None,
+            "down",
+            None,
+        )
+        .unwrap();
+        assert_eq!(entry.number, 3);
+        assert_eq!(entry.direction, Direction::Down);
+        assert_eq!(entry.kind, EntryKind::Ran);
+
+        let entry =
+            entry_from_row(3, "users".to_string(), 0, 0, None, "up", Some("baseline")).unwrap();
+        assert_eq!(entry.kind, EntryKind::Baseline);
+
+        assert!(entry_from_row(-1, "users".to_string(), 0, 0, None, "up", None).is_err());
+        assert!(entry_from_row(3, "users".to_string(), 0, 0, None, "sideways", None).is_err());
+        assert!(entry_from_row(3, "users".to_string(), 0, 0, None, "up", Some("?")).is_err());
+    }
+
+    #[test]
+    fn test_legacy_entries() {
+        let history = legacy_entries(3);
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|e| e.direction == Direction::Up));
+        assert_eq!(current_number(&history), 3);
+        assert!(legacy_entries(0).is_empty());
+    }
+
+    #[test]
+    fn test_find_modified_migrations() {
+        let mut migrations = migrations(3);
+
+        let mut history: Vec<HistoryEntry> = migrations
+            .values()
+            .map(|m| HistoryEntry::new(m, Direction::Up, Duration::from_secs(0)))
+            .collect();
+        // The legacy entry has no checksum so it can't be verified
+        history[0].checksum = None;
+        assert!(find_modified_migrations(&migrations, &history).is_empty());
+
+        for migration in migrations.values_mut() {
+            migration.up.push_str(" -- edited");
+        }
+        assert_eq!(find_modified_migrations(&migrations, &history), vec![2, 3]);
+
+        // Reverted migrations are not checked anymore
+        history.push(HistoryEntry::new(
+            &migrations[&3],
+            Direction::Down,
+            Duration::from_secs(0),
+        ));
+        assert_eq!(find_modified_migrations(&migrations, &history), vec![2]);
+    }
+
+    #[test]
+    fn test_find_out_of_order_migrations() {
+        let mut migrations = Migrations::new();
+        for number in 1..6 {
+            let migration = Migration::new(String::new(), None, number, format!("m_{}", number));
+            migrations.insert(number, migration);
+        }
+        assert!(find_out_of_order_migrations(&migrations, &[]).is_empty());
+
+        let history = vec![
+            entry(1, Direction::Up),
+            entry(3, Direction::Up),
+            entry(4, Direction::Up),
+        ];
+        assert_eq!(find_out_of_order_migrations(&migrations, &history), vec![2]);
+    }
+
+    #[test]
+    fn test_find_pending_repeatables() {
+        let mut repeatables = RepeatableMigrations::new();
+        for name in &["grants", "views"] {
+            let migration = Migration::new_repeatable(format!("-- {}", name), name.to_string());
+            repeatables.insert(name.to_string(), migration);
+        }
+        assert_eq!(
+            find_pending_repeatables(&repeatables, &[]),
+            vec!["grants", "views"]
+        );
+
+        let mut history: Vec<HistoryEntry> = repeatables
+            .values()
+            .map(|m| HistoryEntry::new(m, Direction::Up, Duration::from_secs(0)))
+            .collect();
+        history.push(entry(1, Direction::Up));
+        assert!(find_pending_repeatables(&repeatables, &history).is_empty());
+        // They don't count as versioned migrations
+        assert_eq!(history[0].kind, EntryKind::Repeatable);
+        assert_eq!(applied_numbers(&history).len(), 1);
+        assert!(find_dirty_migrations(&history).is_empty());
+
+        repeatables
+            .get_mut("views")
+            .unwrap()
+            .up
+            .push_str(" -- edited");
+        assert_eq!(
+            find_pending_repeatables(&repeatables, &history),
+            vec!["views"]
+        );
+    }
+}
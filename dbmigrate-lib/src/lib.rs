@@ -1,10 +1,13 @@
 //! Database migrations for Postgres, MySQL, and SQLite.
 //!
 #![deny(missing_docs)]
+#![allow(clippy::result_large_err)]
 
 #[cfg(test)]
 extern crate tempdir;
 
+extern crate dbmigrate_macros;
+
 #[cfg(feature = "mysql_support")]
 extern crate mysql as mysql_client;
 #[cfg(feature = "postgres_support")]
@@ -16,6 +19,7 @@ extern crate postgres_native_tls;
 extern crate regex;
 #[cfg(feature = "sqlite_support")]
 extern crate rusqlite as sqlite_client;
+extern crate sha2;
 #[cfg(feature = "surreal_support")]
 extern crate surrealdb as surreal_client;
 #[cfg(feature = "surreal_support")]
@@ -24,10 +28,16 @@ extern crate url;
 #[macro_use]
 extern crate error_chain;
 
+mod code;
 mod drivers;
 /// All possible errors
 pub mod errors;
 mod files;
+mod history;
+mod migrator;
+mod observer;
+mod placeholders;
+mod plan;
 
 #[cfg(feature = "mysql_support")]
 pub use drivers::mysql::Mysql as MysqlDriver;
@@ -35,6 +45,29 @@ pub use drivers::mysql::Mysql as MysqlDriver;
 pub use drivers::postgres::Postgres as PostgresDriver;
 #[cfg(feature = "sqlite_support")]
 pub use drivers::sqlite::Sqlite as SqliteDriver;
-pub use drivers::{Driver, get_driver};
+pub use drivers::{
+    DEFAULT_TABLE_NAME, Driver, MigrationTable, TableState, driver_scheme, get_driver,
+    get_driver_with_table,
+};
 
-pub use files::{Direction, Migration, Migrations, create_migration, read_migration_files};
+pub use code::{CodeMigration, Connection};
+pub use dbmigrate_macros::embed_migrations;
+pub use files::{
+    DEFAULT_WIDTH, Direction, Migration, Migrations, NO_TRANSACTION_DIRECTIVE, Numbering,
+    RepeatableMigrations, create_migration, detect_width, read_all_migration_files,
+    read_embedded_migrations, read_embedded_migrations_for_driver,
+    read_embedded_migrations_with_code, read_embedded_repeatable_migrations, read_migration_files,
+    read_migration_files_for_driver, read_migration_files_with_code, read_repeatable_migrations,
+    renumber_migration_files,
+};
+pub use history::{
+    EntryKind, HistoryEntry, applied_numbers, current_number, find_modified_migrations,
+    find_out_of_order_migrations, find_pending_repeatables, verify_checksums, verify_order,
+};
+pub use migrator::{
+    DEFAULT_LOCK_TIMEOUT, MigrationReport, MigrationState, MigrationStatus, Migrator, Report,
+    Status,
+};
+pub use observer::Observer;
+pub use placeholders::{Placeholders, substitute_placeholders};
+pub use plan::{Step, plan_down, plan_to, plan_up};
@@ -0,0 +1,1026 @@
+use std::time::{Duration, Instant};
+
+use crate::drivers::{Driver, TableState};
+use crate::errors::{ErrorKind, Result, ResultExt};
+use crate::files::{Direction, Migration, Migrations, RepeatableMigrations};
+use crate::history::{
+    HistoryEntry, applied_numbers, current_number, find_dirty_migrations, find_modified_migrations,
+    find_out_of_order_migrations, find_pending_repeatables, legacy_entries, verify_checksums,
+    verify_order,
+};
+use crate::observer::{Observer, Silent};
+use crate::placeholders::{Placeholders, substitute_placeholders};
+use crate::plan::{Step, plan_down, plan_to, plan_up};
+
+/// How long to wait for the migration lock by default
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A migration that was ran, or would have been for a dry run
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationReport {
+    /// Number of the migration
+    pub number: u64,
+    /// Name of the migration
+    pub name: String,
+    /// In which direction it was ran
+    pub direction: Direction,
+    /// How long it took, zero for a dry run
+    pub duration: Duration,
+    /// Whether it is a repeatable migration, identified by its name only
+    pub repeatable: bool,
+}
+
+/// What a `Migrator` command did
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    /// The migrations ran, in order
+    pub migrations: Vec<MigrationReport>,
+    /// Whether nothing was actually ran
+    pub dry_run: bool,
+}
+
+impl Report {
+    /// Whether there was nothing to run
+    pub fn is_empty(&self) -> bool {
+        self.migrations.is_empty()
+    }
+}
+
+/// Where a migration file stands compared to the database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MigrationState {
+    /// Applied and unchanged since
+    Applied,
+    /// Applied but its file was modified since
+    Modified,
+    /// Not applied yet
+    Pending,
+    /// Not applied even though a newer migration was
+    OutOfOrder,
+}
+
+/// The state of a single migration file
+#[derive(Debug, Clone, PartialEq)]
+pub struct MigrationStatus {
+    /// Number of the migration
+    pub number: u64,
+    /// Name of the migration
+    pub name: String,
+    /// Where it stands
+    pub state: MigrationState,
+}
+
+/// The state of all the migration files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// The current migration number, 0 if nothing was applied
+    pub current: u64,
+    /// All the migrations, oldest first
+    pub migrations: Vec<MigrationStatus>,
+    /// Applied migrations without files, when the database is ahead of them
+    pub missing: Vec<u64>,
+    /// Migrations that started running but never finished, leaving the
+    /// database possibly partially migrated until `force` is used
+    pub dirty: Vec<u64>,
+    /// The repeatable migrations, pending if they never ran or changed since
+    pub repeatables: Vec<MigrationStatus>,
+}
+
+/// Runs migrations against a database, the way the CLI does.
+/// When a migration fails, the commands error with a `MigrationFailed` kind
+/// reporting the migrations that ran before it.
+pub struct Migrator {
+    driver: Box<dyn Driver>,
+    migrations: Migrations,
+    repeatables: RepeatableMigrations,
+    observer: Box<dyn Observer>,
+    lock_timeout: Option<Duration>,
+    allow_out_of_order: bool,
+    dry_run: bool,
+    placeholders: Option<Placeholders>,
+    table_ready: bool,
+}
+
+impl Migrator {
+    /// Creates a migrator for the given database and migrations
+    pub fn new(driver: Box<dyn Driver>, migrations: Migrations) -> Migrator {
+        Migrator {
+            driver,
+            migrations,
+            repeatables: RepeatableMigrations::new(),
+            observer: Box::new(Silent),
+            lock_timeout: Some(DEFAULT_LOCK_TIMEOUT),
+            allow_out_of_order: false,
+            dry_run: false,
+            placeholders: None,
+            table_ready: false,
+        }
+    }
+
+    /// Sets the repeatable migrations, ran by `up` after the versioned ones
+    /// whenever their content changed
+    pub fn repeatable_migrations(mut self, repeatables: RepeatableMigrations) -> Migrator {
+        self.repeatables = repeatables;
+        self
+    }
+
+    /// Sets the observer notified of the progress of the commands
+    pub fn observer(mut self, observer: Box<dyn Observer>) -> Migrator {
+        self.observer = observer;
+        self
+    }
+
+    /// How long commands changing the database wait for the migration lock.
+    /// `None` disables locking, `DEFAULT_LOCK_TIMEOUT` is used otherwise
+    pub fn lock_timeout(mut self, timeout: Option<Duration>) -> Migrator {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Whether to apply migrations older than the current one instead of erroring
+    pub fn allow_out_of_order(mut self, allow: bool) -> Migrator {
+        self.allow_out_of_order = allow;
+        self
+    }
+
+    /// Whether to only report what would be ran without touching the database
+    pub fn dry_run(mut self, dry_run: bool) -> Migrator {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the values of the `${name}` placeholders, replaced in the migrations
+    /// about to run so the ones that won't don't need any.
+    /// Without values the content of the migrations runs as it is.
+    pub fn placeholders(mut self, values: Placeholders) -> Migrator {
+        self.placeholders = Some(values);
+        self
+    }
+
+    /// The migrations this migrator runs
+    pub fn migrations(&self) -> &Migrations {
+        &self.migrations
+    }
+
+    /// The migration a report entry is about, if its file is still there
+    pub fn migration(&self, report: &MigrationReport) -> Option<&Migration> {
+        if report.repeatable {
+            self.repeatables.get(&report.name)
+        } else {
+            self.migrations.get(&report.number)
+        }
+    }
+
+    /// The content a report entry ran, or would run for a dry run, with its
+    /// placeholders replaced
+    pub fn content(&self, report: &MigrationReport) -> Result<String> {
+        let migration = match self.migration(report) {
+            Some(migration) => migration,
+            None => bail!("Migration {} has no file", report.name),
+        };
+        let content = migration.get_content(report.direction)?;
+        match self.placeholders {
+            Some(ref values) => substitute_placeholders(content, values)
+                .chain_err(|| format!("Invalid {}", migration.get_filename(report.direction))),
+            None => Ok(content.to_string()),
+        }
+    }
+
+    /// The underlying driver. The migration table is only created by the commands.
+    pub fn driver(&mut self) -> &mut dyn Driver {
+        &mut *self.driver
+    }
+
+    /// Applies the pending migrations, only the next `steps` ones if given.
+    /// Once all of them are applied, the repeatable migrations that never ran or
+    /// changed since are ran too.
+    /// Errors if applied migrations were modified, or if some are out of order
+    /// unless allowed.
+    pub fn up(&mut self, steps: Option<usize>) -> Result<Report> {
+        let allow_out_of_order = self.allow_out_of_order;
+        let (migrations, repeatables, mut runner) = self.runner_with_repeatables();
+        runner.execute(|history| {
+            verify_checksums(migrations, &history)?;
+            if !allow_out_of_order {
+                verify_order(migrations, &history)?;
+            }
+            let applied = applied_numbers(&history);
+            let mut planned = plan_up(migrations, &applied, steps);
+            if planned.len() == plan_up(migrations, &applied, None).len() {
+                for name in find_pending_repeatables(repeatables, &history) {
+                    planned.push(Step {
+                        migration: &repeatables[&name],
+                        direction: Direction::Up,
+                    });
+                }
+            }
+            Ok(planned)
+        })
+    }
+
+    /// Reverts the applied migrations, only the last `steps` ones if given
+    pub fn down(&mut self, steps: Option<usize>) -> Result<Report> {
+        let (migrations, mut runner) = self.runner();
+        runner.execute(|history| plan_down(migrations, &applied_numbers(&history), steps))
+    }
+
+    /// Applies or reverts migrations until `target` is the current one, 0 reverting
+    /// everything. Checks the applied migrations like `up` if any needs to be applied.
+    pub fn to(&mut self, target: u64) -> Result<Report> {
+        let allow_out_of_order = self.allow_out_of_order;
+        let (migrations, mut runner) = self.runner();
+        runner.execute(|history| {
+            let steps = plan_to(migrations, &applied_numbers(&history), target)?;
+            if steps.iter().any(|s| s.direction == Direction::Up) {
+                verify_checksums(migrations, &history)?;
+                if !allow_out_of_order {
+                    verify_order(migrations, &history)?;
+                }
+            }
+            Ok(steps)
+        })
+    }
+
+    /// Reverts the current migration and applies it again
+    pub fn redo(&mut self) -> Result<Report> {
+        let (migrations, mut runner) = self.runner();
+        runner.execute(|history| {
+            let mut steps = plan_down(migrations, &applied_numbers(&history), Some(1))?;
+            if let Some(step) = steps.first().cloned() {
+                steps.push(Step {
+                    migration: step.migration,
+                    direction: Direction::Up,
+                });
+            }
+            Ok(steps)
+        })
+    }
+
+    /// Reverts the current migration
+    pub fn revert(&mut self) -> Result<Report> {
+        self.down(Some(1))
+    }
+
+    /// Records the migrations up to `target` as applied without running them,
+    /// for databases whose schema already matches them.
+    /// Refuses to if migrations were already applied, unless `force` is set in which
+    /// case only the ones that are not applied are recorded.
+    pub fn baseline(&mut self, target: u64, force: bool) -> Result<Report> {
+        let (migrations, mut runner) = self.runner();
+        if !migrations.contains_key(&target) {
+            bail!("Migration {} does not exist", target);
+        }
+        runner.with_lock(|runner| {
+            let history = runner.history()?;
+            let current = current_number(&history);
+            if current != 0 && !force {
+                bail!(
+                    "The database is already at migration {}, baselining it could hide missing migrations",
+                    current
+                );
+            }
+            let entries: Vec<HistoryEntry> = plan_up(migrations, &applied_numbers(&history), None)
+                .into_iter()
+                .filter(|step| step.migration.number <= target)
+                .map(|step| HistoryEntry::baseline(step.migration))
+                .collect();
+            runner.record(&entries)
+        })
+    }
+
+    /// Records migrations as applied or reverted without running them so that
+    /// exactly the ones up to `target` are applied, 0 reverting everything.
+    /// Meant to repair the history by hand after a migration failed midway,
+    /// which also clears the dirty migrations.
+    pub fn force(&mut self, target: u64) -> Result<Report> {
+        let (migrations, mut runner) = self.runner();
+        if target != 0 && !migrations.contains_key(&target) {
+            bail!("Migration {} does not exist", target);
+        }
+        runner.with_lock(|runner| {
+            let history = runner.history()?;
+            let applied = applied_numbers(&history);
+            let name_of = |number: u64| match migrations.get(&number) {
+                Some(migration) => migration.name.clone(),
+                None => history
+                    .iter()
+                    .rev()
+                    .find(|e| e.number == number)
+                    .map(|e| e.name.clone())
+                    .unwrap_or_default(),
+            };
+
+            let mut entries: Vec<HistoryEntry> = applied
+                .iter()
+                .rev()
+                .filter(|n| **n > target)
+                .map(|n| HistoryEntry::forced(*n, &name_of(*n), Direction::Down))
+                .collect();
+            for migration in migrations.range(..=target).map(|(_, m)| m) {
+                if !applied.contains(&migration.number) {
+                    entries.push(HistoryEntry {
+                        checksum: Some(migration.checksum()),
+                        ..HistoryEntry::forced(migration.number, &migration.name, Direction::Up)
+                    });
+                }
+            }
+            // Dirty migrations left as they are still need an entry to be cleared
+            for number in find_dirty_migrations(&history) {
+                if entries.iter().all(|e| e.number != number) {
+                    let direction = if applied.contains(&number) {
+                        Direction::Up
+                    } else {
+                        Direction::Down
+                    };
+                    entries.push(HistoryEntry::forced(number, &name_of(number), direction));
+                }
+            }
+            runner.record(&entries)
+        })
+    }
+
+    /// Errors if applied migrations were modified since they ran
+    pub fn verify(&mut self) -> Result<()> {
+        let history = self.history()?;
+        verify_checksums(&self.migrations, &history)
+    }
+
+    /// Gets the state of every migration file
+    pub fn status(&mut self) -> Result<Status> {
+        let history = self.history()?;
+        let applied = applied_numbers(&history);
+        let modified = find_modified_migrations(&self.migrations, &history);
+        let out_of_order = find_out_of_order_migrations(&self.migrations, &history);
+
+        let migrations = self
+            .migrations
+            .values()
+            .map(|migration| {
+                let state = if modified.contains(&migration.number) {
+                    MigrationState::Modified
+                } else if applied.contains(&migration.number) {
+                    MigrationState::Applied
+                } else if out_of_order.contains(&migration.number) {
+                    MigrationState::OutOfOrder
+                } else {
+                    MigrationState::Pending
+                };
+                MigrationStatus {
+                    number: migration.number,
+                    name: migration.name.clone(),
+                    state,
+                }
+            })
+            .collect();
+
+        let missing = applied
+            .iter()
+            .filter(|n| !self.migrations.contains_key(n))
+            .cloned()
+            .collect();
+
+        let pending = find_pending_repeatables(&self.repeatables, &history);
+        let repeatables = self
+            .repeatables
+            .values()
+            .map(|migration| MigrationStatus {
+                number: migration.number,
+                name: migration.name.clone(),
+                state: if pending.contains(&migration.name) {
+                    MigrationState::Pending
+                } else {
+                    MigrationState::Applied
+                },
+            })
+            .collect();
+
+        Ok(Status {
+            current: current_number(&history),
+            migrations,
+            missing,
+            dirty: find_dirty_migrations(&history),
+            repeatables,
+        })
+    }
+
+    /// Releases the lock left behind by a process killed while holding it, for the
+    /// databases that don't release it by themselves.
+    /// Only meant to be used when no other process is running migrations.
+    pub fn release_stale_lock(&mut self) -> Result<()> {
+        self.driver.release_stale_lock()
+    }
+
+    /// The history for the commands only reading it. The lock is only taken when
+    /// the migration table has to be created or upgraded first.
+    fn history(&mut self) -> Result<Vec<HistoryEntry>> {
+        let (_, mut runner) = self.runner();
+        if !*runner.table_ready && runner.driver.migration_table_state()? != TableState::Ready {
+            runner.with_lock(|_| Ok(()))?;
+        }
+        runner.history()
+    }
+
+    /// Splits the migrations, which the steps borrow, from what runs them
+    fn runner(&mut self) -> (&Migrations, Runner<'_>) {
+        let (migrations, _, runner) = self.runner_with_repeatables();
+        (migrations, runner)
+    }
+
+    /// Same as `runner`, for the commands also running repeatable migrations
+    fn runner_with_repeatables(&mut self) -> (&Migrations, &RepeatableMigrations, Runner<'_>) {
+        let runner = Runner {
+            driver: &mut *self.driver,
+            observer: &mut *self.observer,
+            lock_timeout: self.lock_timeout,
+            dry_run: self.dry_run,
+            placeholders: self.placeholders.as_ref(),
+            table_ready: &mut self.table_ready,
+        };
+        (&self.migrations, &self.repeatables, runner)
+    }
+}
+
+/// Runs the steps planned by a `Migrator` command
+struct Runner<'a> {
+    driver: &'a mut dyn Driver,
+    observer: &'a mut dyn Observer,
+    lock_timeout: Option<Duration>,
+    dry_run: bool,
+    placeholders: Option<&'a Placeholders>,
+    table_ready: &'a mut bool,
+}
+
+impl Runner<'_> {
+    /// Calls `f` while holding the migration lock, unless locking is disabled
+    /// or this is a dry run.
+    /// The migration table is created or upgraded first, under the lock as well,
+    /// so processes starting at once don't do it concurrently.
+    fn with_lock<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let timeout = match self.lock_timeout {
+            // A dry run doesn't write anything, not even the migration table
+            _ if self.dry_run => return f(self),
+            Some(timeout) => timeout,
+            None => {
+                self.ensure_migration_table()?;
+                return f(self);
+            }
+        };
+
+        self.driver.lock(timeout)?;
+        self.observer.lock_acquired();
+        let res = self.ensure_migration_table().and_then(|_| f(self));
+        self.driver.unlock()?;
+        self.observer.lock_released();
+        res
+    }
+
+    fn ensure_migration_table(&mut self) -> Result<()> {
+        if !*self.table_ready {
+            self.driver.ensure_migration_table_exists()?;
+            *self.table_ready = true;
+        }
+        Ok(())
+    }
+
+    /// The migration history. Until the migration table is created or upgraded,
+    /// which a dry run never does, it is read as it will be once it is.
+    fn history(&mut self) -> Result<Vec<HistoryEntry>> {
+        if !*self.table_ready {
+            match self.driver.migration_table_state()? {
+                TableState::Missing => return Ok(Vec::new()),
+                TableState::Legacy(current) => return Ok(legacy_entries(current)),
+                TableState::Outdated | TableState::Ready => (),
+            }
+        }
+        self.driver.get_history()
+    }
+
+    /// Plans the steps from the history and runs them while holding the migration lock
+    fn execute<'m, F>(&mut self, plan: F) -> Result<Report>
+    where
+        F: FnOnce(Vec<HistoryEntry>) -> Result<Vec<Step<'m>>>,
+    {
+        self.with_lock(|runner| {
+            let steps = plan(runner.history()?)?;
+            runner.run_steps(&steps)
+        })
+    }
+
+    /// Adds entries to the history without running anything, or only reports
+    /// them for a dry run
+    fn record(&mut self, entries: &[HistoryEntry]) -> Result<Report> {
+        let mut report = Report {
+            migrations: Vec::new(),
+            dry_run: self.dry_run,
+        };
+
+        for entry in entries {
+            if !self.dry_run {
+                self.driver.add_history_entry(entry)?;
+            }
+            report.migrations.push(MigrationReport {
+                number: entry.number,
+                name: entry.name.clone(),
+                direction: entry.direction,
+                duration: Duration::from_secs(0),
+                repeatable: false,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Runs the steps in order, or only reports them for a dry run.
+    /// Stops at the first failure with a `MigrationFailed` error reporting
+    /// the steps that ran before it.
+    fn run_steps(&mut self, steps: &[Step]) -> Result<Report> {
+        let mut report = Report {
+            migrations: Vec::new(),
+            dry_run: self.dry_run,
+        };
+        // Undefined placeholders error before anything runs
+        let substituted = steps
+            .iter()
+            .map(|step| match self.placeholders {
+                Some(values) => step.migration.with_placeholders(values),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        for (step, substituted) in steps.iter().zip(&substituted) {
+            let migration = substituted.as_ref().unwrap_or(step.migration);
+            let direction = step.direction;
+            let mut duration = Duration::from_secs(0);
+            if !self.dry_run {
+                match self.run_step(migration, direction) {
+                    Ok(elapsed) => duration = elapsed,
+                    Err(e) => return Err(e).chain_err(|| ErrorKind::MigrationFailed(report)),
+                }
+            }
+            report.migrations.push(MigrationReport {
+                number: migration.number,
+                name: migration.name.clone(),
+                direction,
+                duration,
+                repeatable: migration.repeatable,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Runs a single migration, returning how long it took
+    fn run_step(&mut self, migration: &Migration, direction: Direction) -> Result<Duration> {
+        // Stays the last entry of the migration if it doesn't finish.
+        // Migrations in a transaction leave nothing behind when they fail and
+        // repeatable ones run again until they succeed so neither need one.
+        if !migration.repeatable && !self.driver.in_transaction(migration, direction) {
+            self.driver
+                .add_history_entry(&HistoryEntry::started(migration, direction))?;
+        }
+        self.observer.migration_started(migration, direction);
+        let start = Instant::now();
+        let res = self.driver.migrate(migration, direction).chain_err(|| {
+            if migration.repeatable {
+                format!("Failed to run repeatable migration {}", migration.name)
+            } else {
+                format!(
+                    "Failed to run {} migration #{}: {}",
+                    direction, migration.number, migration.name
+                )
+            }
+        });
+        if let Err(e) = res {
+            self.observer.migration_failed(migration, direction, &e);
+            return Err(e);
+        }
+        let duration = start.elapsed();
+        self.observer
+            .migration_finished(migration, direction, duration);
+        Ok(duration)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite_support"))]
+mod tests {
+    use super::{MigrationState, Migrator};
+    use crate::code::{CodeMigration, Connection as CodeConnection};
+    use crate::drivers::sqlite::Sqlite;
+    use crate::drivers::{Driver, MigrationTable, TableState};
+    use crate::errors::{Error, ErrorKind, Result};
+    use crate::files::fixtures::migrations;
+    use crate::files::{Direction, Migration, Migrations, RepeatableMigrations};
+    use crate::history::EntryKind;
+    use crate::observer::Observer;
+    use crate::placeholders::Placeholders;
+    use sqlite_client::Connection;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    fn migrator(count: u64) -> Migrator {
+        let driver = Sqlite::from_connection(
+            Connection::open_in_memory().unwrap(),
+            MigrationTable::default(),
+        )
+        .unwrap();
+        Migrator::new(Box::new(driver), migrations(count)).lock_timeout(None)
+    }
+
+    #[test]
+    fn test_up_and_down() {
+        let mut migrator = migrator(3);
+        let report = migrator.up(Some(2)).unwrap();
+        let numbers: Vec<u64> = report.migrations.iter().map(|m| m.number).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 2);
+
+        let report = migrator.redo().unwrap();
+        let steps: Vec<(u64, Direction)> = report
+            .migrations
+            .iter()
+            .map(|m| (m.number, m.direction))
+            .collect();
+        assert_eq!(steps, vec![(2, Direction::Down), (2, Direction::Up)]);
+
+        assert_eq!(migrator.to(3).unwrap().migrations.len(), 1);
+        assert_eq!(migrator.down(None).unwrap().migrations.len(), 3);
+        assert!(migrator.revert().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let mut migrator = migrator(2).dry_run(true);
+        let report = migrator.up(None).unwrap();
+        assert!(report.dry_run);
+        assert_eq!(report.migrations.len(), 2);
+        assert_eq!(
+            migrator.driver().migration_table_state().unwrap(),
+            TableState::Missing
+        );
+    }
+
+    #[test]
+    fn test_dry_run_leaves_legacy_table_unchanged() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE __dbmigrate_table(id INTEGER, current INTEGER);
+             INSERT INTO __dbmigrate_table (id, current) VALUES (1, 1);",
+        )
+        .unwrap();
+        let driver = Sqlite::from_connection(conn, MigrationTable::default()).unwrap();
+        let mut migrator = Migrator::new(Box::new(driver), migrations(3))
+            .lock_timeout(None)
+            .dry_run(true);
+
+        let report = migrator.up(None).unwrap();
+        let numbers: Vec<u64> = report.migrations.iter().map(|m| m.number).collect();
+        assert_eq!(numbers, vec![2, 3]);
+        assert_eq!(
+            migrator.driver().migration_table_state().unwrap(),
+            TableState::Legacy(1)
+        );
+    }
+
+    #[test]
+    fn test_placeholders_replaced_when_running() {
+        let mut migrator = migrator(1);
+        migrator.migrations.insert(
+            2,
+            Migration::new(
+                "CREATE TABLE ${name} (id INTEGER);".into(),
+                None,
+                2,
+                "named".into(),
+            ),
+        );
+        let mut migrator = migrator.placeholders(Placeholders::new());
+        // Only the migrations about to run need values
+        migrator.to(1).unwrap();
+        assert_eq!(migrator.status().unwrap().current, 1);
+        let err = migrator.up(None).unwrap_err();
+        assert!(format!("{:?}", err).contains("Undefined placeholders: name"));
+        assert_eq!(migrator.status().unwrap().current, 1);
+
+        let mut values = Placeholders::new();
+        values.insert("name".into(), "users".into());
+        let mut migrator = migrator.placeholders(values).dry_run(true);
+        let report = migrator.up(None).unwrap();
+        assert_eq!(
+            migrator.content(&report.migrations[0]).unwrap(),
+            "CREATE TABLE users (id INTEGER);"
+        );
+        let mut migrator = migrator.dry_run(false);
+        migrator.up(None).unwrap();
+        assert_eq!(migrator.status().unwrap().current, 2);
+        // The file content stays as it is
+        assert!(migrator.migrations()[&2].up.contains("${name}"));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut migrator = migrator(3);
+        migrator.to(1).unwrap();
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 1);
+        let states: Vec<MigrationState> = status.migrations.iter().map(|m| m.state).collect();
+        assert_eq!(
+            states,
+            vec![
+                MigrationState::Applied,
+                MigrationState::Pending,
+                MigrationState::Pending
+            ]
+        );
+        assert!(status.missing.is_empty());
+
+        migrator.up(None).unwrap();
+        migrator.migrations.remove(&3);
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 3);
+        assert_eq!(status.migrations.len(), 2);
+        assert_eq!(status.missing, vec![3]);
+    }
+
+    #[test]
+    fn test_repeatable_migrations() {
+        let repeatables: RepeatableMigrations = ["a_view", "b_view"]
+            .iter()
+            .map(|name| {
+                let content = format!("CREATE VIEW IF NOT EXISTS {} AS SELECT 1;", name);
+                let migration = Migration::new_repeatable(content, name.to_string());
+                (name.to_string(), migration)
+            })
+            .collect();
+        let mut migrator = migrator(2).repeatable_migrations(repeatables);
+
+        // They wait for all the versioned migrations to be applied
+        let report = migrator.up(Some(1)).unwrap();
+        assert_eq!(report.migrations.len(), 1);
+        let report = migrator.up(None).unwrap();
+        let ran: Vec<(u64, &str, bool)> = report
+            .migrations
+            .iter()
+            .map(|m| (m.number, m.name.as_str(), m.repeatable))
+            .collect();
+        assert_eq!(
+            ran,
+            vec![
+                (2, "table_2", false),
+                (0, "a_view", true),
+                (0, "b_view", true)
+            ]
+        );
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 2);
+        assert!(
+            status
+                .repeatables
+                .iter()
+                .all(|m| m.state == MigrationState::Applied)
+        );
+        assert!(migrator.up(None).unwrap().is_empty());
+
+        // Only the changed ones run again
+        migrator.repeatables.get_mut("b_view").unwrap().up =
+            "DROP VIEW b_view; CREATE VIEW b_view AS SELECT 2;".to_string();
+        assert_eq!(
+            migrator.status().unwrap().repeatables[1].state,
+            MigrationState::Pending
+        );
+        let report = migrator.up(None).unwrap();
+        assert_eq!(report.migrations.len(), 1);
+        assert_eq!(report.migrations[0].name, "b_view");
+        assert_eq!(migrator.down(None).unwrap().migrations.len(), 2);
+    }
+
+    /// Copies the ids of table_1 to table_2, doubling them
+    struct DoubleIds;
+
+    impl CodeMigration for DoubleIds {
+        fn up(&self, connection: CodeConnection) -> Result<()> {
+            let CodeConnection::Sqlite(conn) = connection else {
+                bail!("Only SQLite is supported");
+            };
+            let ids: Vec<i64> = {
+                let mut statement = conn.prepare("SELECT id FROM table_1;")?;
+                let rows = statement.query_map(&[], |row| row.get(0))?;
+                rows.collect::<std::result::Result<_, _>>()?
+            };
+            for id in ids {
+                conn.execute("INSERT INTO table_2 VALUES (?);", &[&(id * 2)])?;
+            }
+            Ok(())
+        }
+
+        fn down(&self, connection: CodeConnection) -> Result<()> {
+            let CodeConnection::Sqlite(conn) = connection else {
+                bail!("Only SQLite is supported");
+            };
+            let doubled: i64 =
+                conn.query_row("SELECT COUNT(*) FROM table_2 WHERE id = 42;", &[], |row| {
+                    row.get(0)
+                })?;
+            if doubled != 1 {
+                bail!("The ids were not doubled");
+            }
+            conn.execute("DELETE FROM table_2;", &[])?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_code_migrations() {
+        let mut migrator = migrator(2);
+        migrator
+            .migrations
+            .get_mut(&1)
+            .unwrap()
+            .up
+            .push_str(" INSERT INTO table_1 VALUES (21);");
+        migrator.migrations.insert(
+            3,
+            Migration::new_code(3, "double_ids".to_string(), Box::new(DoubleIds)),
+        );
+
+        let report = migrator.up(None).unwrap();
+        assert_eq!(report.migrations.len(), 3);
+        let history = migrator.driver().get_history().unwrap();
+        let last = history.last().unwrap();
+        assert_eq!((last.number, last.name.as_str()), (3, "double_ids"));
+        assert_eq!(last.kind, EntryKind::Ran);
+
+        // Reverting checks what running it did
+        assert_eq!(migrator.revert().unwrap().migrations[0].number, 3);
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 2);
+        assert_eq!(migrator.up(None).unwrap().migrations.len(), 1);
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_baseline() {
+        let mut migrator = migrator(3);
+        assert!(migrator.baseline(4, false).is_err());
+
+        let report = migrator.baseline(2, false).unwrap();
+        let numbers: Vec<u64> = report.migrations.iter().map(|m| m.number).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        let history = migrator.driver().get_history().unwrap();
+        assert!(history.iter().all(|e| e.kind == EntryKind::Baseline));
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 2);
+
+        // Nothing was ran so reverting fails to drop the table of migration 2
+        assert!(migrator.down(Some(1)).is_err());
+
+        assert!(migrator.baseline(3, false).is_err());
+        // Only the migrations that are not applied are recorded
+        let report = migrator.baseline(3, true).unwrap();
+        assert_eq!(report.migrations.len(), 1);
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_force() {
+        let mut migrator = migrator(3);
+        migrator.migrations.get_mut(&3).unwrap().up = "NOT SQL;".to_string();
+        assert!(migrator.up(None).is_err());
+        // It was rolled back
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 2);
+        assert!(status.dirty.is_empty());
+
+        migrator.migrations.get_mut(&3).unwrap().up_in_transaction = false;
+        assert!(migrator.up(None).is_err());
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 2);
+        assert_eq!(status.dirty, vec![3]);
+        assert!(migrator.force(4).is_err());
+
+        // Pretend migration 3 was fixed by hand
+        let report = migrator.force(3).unwrap();
+        assert_eq!(report.migrations.len(), 1);
+        let status = migrator.status().unwrap();
+        assert_eq!(status.current, 3);
+        assert!(status.dirty.is_empty());
+
+        let report = migrator.force(1).unwrap();
+        let steps: Vec<(u64, Direction)> = report
+            .migrations
+            .iter()
+            .map(|m| (m.number, m.direction))
+            .collect();
+        assert_eq!(steps, vec![(3, Direction::Down), (2, Direction::Down)]);
+        assert_eq!(migrator.driver().get_current_number().unwrap(), 1);
+        let history = migrator.driver().get_history().unwrap();
+        assert_eq!(history.last().unwrap().kind, EntryKind::Forced);
+    }
+
+    #[test]
+    fn test_failure_reports_ran_migrations() {
+        let mut migrator = migrator(3);
+        migrator.migrations.get_mut(&3).unwrap().up = "NOT SQL;".to_string();
+        match migrator.up(None) {
+            Err(Error(ErrorKind::MigrationFailed(report), _)) => {
+                let numbers: Vec<u64> = report.migrations.iter().map(|m| m.number).collect();
+                assert_eq!(numbers, vec![1, 2]);
+            }
+            res => panic!("expected a migration failed error, got {:?}", res),
+        }
+    }
+
+    struct Events(Rc<RefCell<Vec<String>>>);
+
+    impl Observer for Events {
+        fn lock_acquired(&mut self) {
+            self.0.borrow_mut().push("locked".to_string());
+        }
+        fn lock_released(&mut self) {
+            self.0.borrow_mut().push("unlocked".to_string());
+        }
+        fn migration_started(&mut self, migration: &Migration, direction: Direction) {
+            let event = format!("started {} {}", direction, migration.number);
+            self.0.borrow_mut().push(event);
+        }
+        fn migration_finished(&mut self, migration: &Migration, direction: Direction, _: Duration) {
+            let event = format!("finished {} {}", direction, migration.number);
+            self.0.borrow_mut().push(event);
+        }
+        fn migration_failed(&mut self, migration: &Migration, direction: Direction, _: &Error) {
+            let event = format!("failed {} {}", direction, migration.number);
+            self.0.borrow_mut().push(event);
+        }
+    }
+
+    #[test]
+    fn test_observer() {
+        let dir = TempDir::new("sqlite").unwrap();
+        let url = format!("sqlite:/{}", dir.path().join("test.db").display());
+        let mut migrations = Migrations::new();
+        migrations.insert(
+            1,
+            Migration::new(
+                "CREATE TABLE a (id INTEGER);".to_string(),
+                None,
+                1,
+                "a".to_string(),
+            ),
+        );
+        migrations.insert(
+            2,
+            Migration::new(
+                "INSERT INTO nope VALUES (1);".to_string(),
+                None,
+                2,
+                "b".to_string(),
+            ),
+        );
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let driver = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        let mut migrator =
+            Migrator::new(Box::new(driver), migrations).observer(Box::new(Events(events.clone())));
+        assert!(migrator.up(None).is_err());
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "locked",
+                "started up 1",
+                "finished up 1",
+                "started up 2",
+                "failed up 2",
+                "unlocked"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_table_created_under_lock() {
+        let dir = TempDir::new("sqlite").unwrap();
+        let url = format!("sqlite:/{}", dir.path().join("test.db").display());
+        let mut other = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        other.lock(Duration::from_secs(0)).unwrap();
+
+        let driver = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        let mut migrator = Migrator::new(Box::new(driver), Migrations::new())
+            .lock_timeout(Some(Duration::from_secs(0)));
+        assert!(migrator.status().is_err());
+        assert!(other.get_history().is_err());
+
+        other.unlock().unwrap();
+        assert_eq!(migrator.status().unwrap().current, 0);
+        assert!(other.get_history().unwrap().is_empty());
+
+        // Once the table is there, reading it doesn't wait for the lock
+        other.lock(Duration::from_secs(0)).unwrap();
+        let driver = Sqlite::new(&url, MigrationTable::default()).unwrap();
+        let mut migrator = Migrator::new(Box::new(driver), Migrations::new())
+            .lock_timeout(Some(Duration::from_secs(0)));
+        assert_eq!(migrator.status().unwrap().current, 0);
+        assert!(migrator.verify().is_ok());
+        other.unlock().unwrap();
+    }
+}
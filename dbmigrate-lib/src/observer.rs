@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use crate::errors::Error;
+use crate::files::{Direction, Migration};
+
+/// Gets notified of what a `Migrator` is doing, to log it or emit metrics.
+/// Every method does nothing by default so only the interesting ones need
+/// to be implemented.
+/// Nothing is notified for dry runs since nothing runs.
+pub trait Observer {
+    /// The migration lock was acquired
+    fn lock_acquired(&mut self) {}
+    /// The migration lock was released
+    fn lock_released(&mut self) {}
+    /// A migration is about to run
+    fn migration_started(&mut self, _migration: &Migration, _direction: Direction) {}
+    /// A migration ran successfully
+    fn migration_finished(
+        &mut self,
+        _migration: &Migration,
+        _direction: Direction,
+        _duration: Duration,
+    ) {
+    }
+    /// A migration failed: nothing else runs after it and the error is returned
+    /// by the `Migrator` command
+    fn migration_failed(&mut self, _migration: &Migration, _direction: Direction, _error: &Error) {}
+}
+
+/// The observer used when none is given
+pub(crate) struct Silent;
+
+impl Observer for Silent {}
@@ -0,0 +1,80 @@
+use std::collections::BTreeMap;
+
+use crate::errors::Result;
+use regex::Regex;
+
+/// Values of the `${name}` placeholders of migration files, by name
+pub type Placeholders = BTreeMap<String, String>;
+
+/// Replaces the `${name}` placeholders of `content` by their value, erroring
+/// with all the placeholders that have none.
+/// `$${name}` is kept as a literal `${name}`.
+pub fn substitute_placeholders(content: &str, values: &Placeholders) -> Result<String> {
+    let re = Regex::new(r"\$(?P<escaped>\$)?\{(?P<name>[A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+
+    let mut substituted = String::with_capacity(content.len());
+    let mut undefined = Vec::new();
+    let mut last = 0;
+    for caps in re.captures_iter(content) {
+        let whole = caps.get(0).unwrap();
+        let name = caps.name("name").unwrap().as_str();
+        substituted.push_str(&content[last..whole.start()]);
+        if caps.name("escaped").is_some() {
+            substituted.push_str(&whole.as_str()[1..]);
+        } else {
+            match values.get(name) {
+                Some(value) => substituted.push_str(value),
+                None => undefined.push(name),
+            }
+        }
+        last = whole.end();
+    }
+    substituted.push_str(&content[last..]);
+
+    if !undefined.is_empty() {
+        undefined.sort_unstable();
+        undefined.dedup();
+        bail!("Undefined placeholders: {}", undefined.join(", "));
+    }
+    Ok(substituted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Placeholders, substitute_placeholders};
+
+    #[test]
+    fn test_substitute_placeholders() {
+        let mut values = Placeholders::new();
+        values.insert("schema".to_string(), "billing".to_string());
+        values.insert("app_role".to_string(), "billing_app".to_string());
+
+        assert_eq!(
+            substitute_placeholders(
+                "CREATE TABLE ${schema}.invoices (id INT);\nGRANT SELECT ON ${schema}.invoices TO ${app_role};",
+                &values
+            )
+            .unwrap(),
+            "CREATE TABLE billing.invoices (id INT);\nGRANT SELECT ON billing.invoices TO billing_app;"
+        );
+        // Dollar quoting and escaped placeholders are left alone
+        assert_eq!(
+            substitute_placeholders("DO $$ SELECT $1, $${schema}; $$;", &values).unwrap(),
+            "DO $$ SELECT $1, ${schema}; $$;"
+        );
+        assert_eq!(
+            substitute_placeholders("SELECT 1;", &Placeholders::new()).unwrap(),
+            "SELECT 1;"
+        );
+    }
+
+    #[test]
+    fn test_undefined_placeholders() {
+        let err = substitute_placeholders(
+            "GRANT ALL ON ${schema}.a TO ${role}; GRANT ALL ON ${schema}.b TO ${role};",
+            &Placeholders::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Undefined placeholders: role, schema");
+    }
+}
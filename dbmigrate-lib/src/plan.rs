@@ -0,0 +1,172 @@
+use std::collections::BTreeSet;
+
+use crate::errors::Result;
+use crate::files::{Direction, Migration, Migrations};
+
+/// A migration to run in a given direction
+#[derive(Debug, Clone, Copy)]
+pub struct Step<'a> {
+    /// The migration to run
+    pub migration: &'a Migration,
+    /// Which way to run it
+    pub direction: Direction,
+}
+
+/// The step reverting an applied migration, erroring if it can't be reverted
+fn down_step(migrations: &Migrations, number: u64) -> Result<Step<'_>> {
+    let migration = match migrations.get(&number) {
+        Some(m) => m,
+        None => bail!("Files for applied migration {} are missing", number),
+    };
+    if !migration.has_down() {
+        bail!("Migration {} has no down file", number);
+    }
+    Ok(Step {
+        migration,
+        direction: Direction::Down,
+    })
+}
+
+/// Computes the migrations to run, in order, to go from the `applied` ones
+/// to having exactly the migrations up to `target` applied.
+/// A `target` of 0 means reverting everything.
+/// Errors if the target doesn't exist or if a migration that needs to be
+/// reverted has no down file, before anything is ran.
+pub fn plan_to<'a>(
+    migrations: &'a Migrations,
+    applied: &BTreeSet<u64>,
+    target: u64,
+) -> Result<Vec<Step<'a>>> {
+    if target != 0 && !migrations.contains_key(&target) {
+        bail!("Migration {} does not exist", target);
+    }
+
+    let mut steps = applied
+        .iter()
+        .rev()
+        .filter(|n| **n > target)
+        .map(|number| down_step(migrations, *number))
+        .collect::<Result<Vec<_>>>()?;
+
+    for migration in migrations.range(..=target).map(|(_, m)| m) {
+        if !applied.contains(&migration.number) {
+            steps.push(Step {
+                migration,
+                direction: Direction::Up,
+            });
+        }
+    }
+
+    Ok(steps)
+}
+
+/// Computes the migrations to apply, oldest first, limited to the next `steps`
+/// ones if given
+pub fn plan_up<'a>(
+    migrations: &'a Migrations,
+    applied: &BTreeSet<u64>,
+    steps: Option<usize>,
+) -> Vec<Step<'a>> {
+    migrations
+        .values()
+        .filter(|m| !applied.contains(&m.number))
+        .take(steps.unwrap_or(usize::MAX))
+        .map(|migration| Step {
+            migration,
+            direction: Direction::Up,
+        })
+        .collect()
+}
+
+/// Computes the migrations to revert, newest first, limited to the last `steps`
+/// ones if given.
+/// Errors if one of them has no down file, before anything is ran.
+pub fn plan_down<'a>(
+    migrations: &'a Migrations,
+    applied: &BTreeSet<u64>,
+    steps: Option<usize>,
+) -> Result<Vec<Step<'a>>> {
+    applied
+        .iter()
+        .rev()
+        .take(steps.unwrap_or(usize::MAX))
+        .map(|number| down_step(migrations, *number))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan_down, plan_to, plan_up};
+    use crate::files::fixtures::migrations;
+    use crate::files::{Direction, Migrations};
+    use std::collections::BTreeSet;
+
+    fn plan(migrations: &Migrations, applied: &[u64], target: u64) -> Vec<(u64, Direction)> {
+        let applied: BTreeSet<u64> = applied.iter().cloned().collect();
+        plan_to(migrations, &applied, target)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.migration.number, s.direction))
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_up() {
+        let migrations = migrations(4);
+        assert_eq!(
+            plan(&migrations, &[1], 3),
+            vec![(2, Direction::Up), (3, Direction::Up)]
+        );
+        assert!(plan(&migrations, &[1, 2, 3], 3).is_empty());
+    }
+
+    #[test]
+    fn test_plan_down() {
+        let migrations = migrations(4);
+        assert_eq!(
+            plan(&migrations, &[1, 2, 3, 4], 2),
+            vec![(4, Direction::Down), (3, Direction::Down)]
+        );
+        assert_eq!(plan(&migrations, &[1], 0), vec![(1, Direction::Down)]);
+    }
+
+    #[test]
+    fn test_plan_invalid_target() {
+        let migrations = migrations(2);
+        assert!(plan_to(&migrations, &BTreeSet::new(), 3).is_err());
+    }
+
+    #[test]
+    fn test_plan_refuses_missing_down_file() {
+        let mut migrations = migrations(3);
+        migrations.get_mut(&2).unwrap().down = None;
+        let applied: BTreeSet<u64> = [1, 2, 3].iter().cloned().collect();
+
+        assert!(plan_to(&migrations, &applied, 1).is_err());
+        // Migration 2 doesn't need to be reverted to go to 2
+        assert_eq!(plan_to(&migrations, &applied, 2).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_plan_steps() {
+        let migrations = migrations(4);
+        let applied: BTreeSet<u64> = [1, 2].iter().cloned().collect();
+
+        let up: Vec<u64> = plan_up(&migrations, &applied, Some(1))
+            .iter()
+            .map(|s| s.migration.number)
+            .collect();
+        assert_eq!(up, vec![3]);
+        assert_eq!(plan_up(&migrations, &applied, None).len(), 2);
+        assert_eq!(plan_up(&migrations, &applied, Some(10)).len(), 2);
+
+        let down: Vec<u64> = plan_down(&migrations, &applied, Some(1))
+            .unwrap()
+            .iter()
+            .map(|s| s.migration.number)
+            .collect();
+        assert_eq!(down, vec![2]);
+        assert_eq!(plan_down(&migrations, &applied, None).unwrap().len(), 2);
+        assert_eq!(plan_down(&migrations, &applied, Some(10)).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+//! Macros for dbmigrate-lib, use them through its re-exports.
+//!
+#![deny(missing_docs)]
+
+extern crate proc_macro;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use proc_macro::TokenStream;
+use regex::Regex;
+use syn::LitStr;
+
+/// Timestamps numbers are written as `YYYYMMDDHHMMSS` so they always have 14 digits
+const TIMESTAMP_DIGITS: usize = 14;
+
+/// Embeds the SQL files of a migration directory in the binary.
+///
+/// The path is relative to the directory containing the `Cargo.toml` of the crate
+/// calling the macro. It expands to the `&[(filename, content)]` list of the files,
+/// to give to `dbmigrate_lib::read_embedded_migrations` or
+/// `dbmigrate_lib::read_embedded_migrations_for_driver` once the database is known,
+/// and to `dbmigrate_lib::read_embedded_repeatable_migrations` for the `R.<name>.sql` ones.
+/// Those parse the files with the same rules as `read_migration_files`, and the
+/// filenames and numbers are already checked when compiling: every SQL file
+/// has to be a migration, without gaps nor duplicated numbers.
+///
+/// Editing a migration triggers a rebuild but adding one doesn't: touch the file
+/// calling the macro, or add a `build.rs` with `cargo:rerun-if-changed=migrations`.
+#[proc_macro]
+pub fn embed_migrations(input: TokenStream) -> TokenStream {
+    match expand(input) {
+        Ok(output) => output,
+        Err(message) => format!("compile_error!({:?})", message).parse().unwrap(),
+    }
+}
+
+fn expand(input: TokenStream) -> Result<TokenStream, String> {
+    let relative_path = syn::parse::<LitStr>(input)
+        .map_err(|_| "embed_migrations! expects a path as a string literal")?
+        .value();
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR")
+        .map_err(|_| "CARGO_MANIFEST_DIR is not set, is cargo building this crate?")?;
+    let path = PathBuf::from(manifest_dir).join(relative_path);
+    let entries =
+        fs::read_dir(&path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let filename = entry.file_name().to_string_lossy().to_string();
+        // Only SQL files can be migrations, versioned or repeatable, the library
+        // sorts out the rest
+        if filename.ends_with(".sql") {
+            files.push((filename, entry.path()));
+        }
+    }
+    files.sort();
+    let filenames: Vec<&str> = files
+        .iter()
+        .map(|(filename, _)| filename.as_str())
+        .collect();
+    check_filenames(&filenames)?;
+
+    let files: Vec<String> = files
+        .iter()
+        .map(|(filename, path)| format!("({:?}, include_str!({:?}))", filename, path))
+        .collect();
+    Ok(format!("&[{}]", files.join(", ")).parse().unwrap())
+}
+
+/// A versioned migration file: its number, the driver it is specific to if any,
+/// and whether it is the up one
+type FileKey = (u64, Option<String>, bool);
+
+/// Parses a versioned migration filename like `parse_filename` in dbmigrate-lib,
+/// None if it isn't one
+fn parse_filename(filename: &str) -> Result<Option<FileKey>, String> {
+    let re = Regex::new(
+        r"^(?P<number>[0-9]+)\.(?P<name>[_0-9a-zA-Z]*)(\.(?P<driver>[a-z]+))?\.(?P<direction>up|down)\.sql$",
+    )
+    .unwrap();
+    let caps = match re.captures(filename) {
+        Some(caps) => caps,
+        None => return Ok(None),
+    };
+    let number = caps["number"]
+        .parse::<u64>()
+        .map_err(|_| format!("File {} has a number that is too large", filename))?;
+    let driver = caps.name("driver").map(|d| d.as_str().to_string());
+    Ok(Some((number, driver, &caps["direction"] == "up")))
+}
+
+/// Checks the SQL files are all migrations, versioned or repeatable, and that
+/// the versioned ones don't share a number nor leave gaps between sequential numbers
+fn check_filenames(filenames: &[&str]) -> Result<(), String> {
+    let repeatable = Regex::new(r"^R\.[_0-9a-zA-Z]+\.sql$").unwrap();
+    let mut files: BTreeMap<FileKey, &str> = BTreeMap::new();
+    for filename in filenames {
+        let key = match parse_filename(filename)? {
+            Some(key) => key,
+            None if repeatable.is_match(filename) => continue,
+            None => return Err(format!("File {} has an invalid filename", filename)),
+        };
+        if let Some(other) = files.insert(key, filename) {
+            return Err(format!(
+                "Files {} and {} have the same number",
+                other, filename
+            ));
+        }
+    }
+
+    let numbers: BTreeSet<u64> = files.keys().map(|(number, _, _)| *number).collect();
+    // Only sequential numbers need to be contiguous, timestamps can have gaps
+    let sequential = numbers
+        .iter()
+        .filter(|number| number.to_string().len() != TIMESTAMP_DIGITS);
+    for (index, number) in sequential.enumerate() {
+        let expected_number = index as u64 + 1;
+        if *number != expected_number {
+            return Err(format!(
+                "Files for migration {} are missing",
+                expected_number
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_filenames;
+
+    #[test]
+    fn test_check_filenames() {
+        assert!(
+            check_filenames(&[
+                "0001.users.up.sql",
+                "0001.users.down.sql",
+                "0001.users.sqlite.up.sql",
+                "0002.vacuum.up.sql",
+                "20240102030405.later.up.sql",
+                "R.active_users.sql",
+            ])
+            .is_ok()
+        );
+        assert_eq!(
+            check_filenames(&["0001.users.up.sql", "notes.sql"]).unwrap_err(),
+            "File notes.sql has an invalid filename"
+        );
+        assert_eq!(
+            check_filenames(&["0001.users.up.sql", "1.people.up.sql"]).unwrap_err(),
+            "Files 0001.users.up.sql and 1.people.up.sql have the same number"
+        );
+        assert_eq!(
+            check_filenames(&["0001.users.up.sql", "0003.posts.up.sql"]).unwrap_err(),
+            "Files for migration 2 are missing"
+        );
+    }
+}
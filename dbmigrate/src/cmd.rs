@@ -1,138 +1,517 @@
+use std::io::{self, BufRead, Write};
 use std::path::Path;
-use std::time::Instant;
 
-use dbmigrate_lib::{create_migration, Direction, Driver, Migrations};
-use errors::Result;
-use print;
+use dbmigrate_lib::errors::ErrorKind as LibErrorKind;
+use dbmigrate_lib::{
+    create_migration, renumber_migration_files, Direction, Migration, MigrationReport,
+    MigrationState, Migrations, Migrator, Numbering, Report, Status,
+};
+use errors::{Error, ErrorKind, Result, ResultExt};
+use print::{self, Format};
+use serde_json::Value;
 
-// Does the whole migration thingy, along with timing and handling errors
-macro_rules! migrate {
-    ($driver: ident, $migration_file: ident, $direction: expr) => {
-        println!(
-            "Running {} migration #{}: {}",
-            $direction.to_string(),
-            $migration_file.number,
-            $migration_file.name
-        );
-        let res = {
-            let start = Instant::now();
-
-            let content = match $direction {
-                Direction::Up => $migration_file.up.clone(),
-                Direction::Down => $migration_file
-                    .down
-                    .clone()
-                    .expect("Down migration content missing"),
-            };
-            let index = if $direction == Direction::Up {
-                $migration_file.number
-            } else {
-                ($migration_file.number - 1)
-            };
-
-            match $driver.migrate(content, index) {
-                Err(e) => Err(e),
-                Ok(_) => {
-                    let duration = start.elapsed();
-                    print::success(&format!("> Done in {} second(s)", duration.as_secs()));
-                    Ok(())
-                }
-            }
-        };
-        if res.is_err() {
-            return res.map_err(|e| e.into());
-        }
-    };
-}
-
-pub fn create(migration_files: &Migrations, path: &Path, slug: &str) -> Result<()> {
-    let current_number = migration_files.keys().cloned().max().unwrap_or(0u32);
-    let number = current_number + 1;
-    match create_migration(path, slug, number) {
+pub fn create(
+    migration_files: &Migrations,
+    path: &Path,
+    slug: &str,
+    numbering: Numbering,
+    width: usize,
+    format: Format,
+) -> Result<()> {
+    let number = numbering.next_number(migration_files);
+    match create_migration(path, slug, number, width) {
         Err(e) => Err(e.into()),
-        Ok(_) => {
+        Ok(migration) => {
+            if format == Format::Json {
+                print::json(&json!({
+                    "command": "create",
+                    "number": migration.number,
+                    "name": migration.name,
+                    "files": [
+                        migration.get_filename(Direction::Up),
+                        migration.get_filename(Direction::Down),
+                    ],
+                }));
+                return Ok(());
+            }
+            println!("Created {}", migration.get_filename(Direction::Up));
+            println!("Created {}", migration.get_filename(Direction::Down));
             print::success("Migration files successfully created!");
             Ok(())
         }
     }
 }
 
-pub fn status(mut driver: Box<dyn Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number().max(0);
-    if current == 0 {
+pub fn renumber(path: &Path, width: usize, format: Format) -> Result<()> {
+    let renames = renumber_migration_files(path, width)?;
+    if format == Format::Json {
+        let renamed: Vec<Value> = renames
+            .iter()
+            .map(|(old, new)| json!({"from": old, "to": new}))
+            .collect();
+        print::json(&json!({"command": "renumber", "renamed": renamed}));
+        return Ok(());
+    }
+    if renames.is_empty() {
+        print::success(&format!(
+            "Migrations are already padded to {} digits",
+            width
+        ));
+        return Ok(());
+    }
+    for (old, new) in renames {
+        println!("Renamed {} to {}", old, new);
+    }
+    print::success("Migration files successfully renumbered!");
+    Ok(())
+}
+
+fn state_name(state: MigrationState) -> &'static str {
+    match state {
+        MigrationState::Applied => "applied",
+        MigrationState::Modified => "modified",
+        MigrationState::Pending => "pending",
+        MigrationState::OutOfOrder => "out_of_order",
+    }
+}
+
+pub fn status(migrator: &mut Migrator, format: Format) -> Result<()> {
+    let status = migrator.status()?;
+    if format == Format::Json {
+        let migrations: Vec<Value> = status
+            .migrations
+            .iter()
+            .map(|m| json!({"number": m.number, "name": m.name, "state": state_name(m.state)}))
+            .collect();
+        let repeatables: Vec<Value> = status
+            .repeatables
+            .iter()
+            .map(|m| json!({"name": m.name, "state": state_name(m.state)}))
+            .collect();
+        print::json(&json!({
+            "command": "status",
+            "current": status.current,
+            "applied": numbers_in(&status, &[MigrationState::Applied, MigrationState::Modified]),
+            "pending": numbers_in(&status, &[MigrationState::Pending, MigrationState::OutOfOrder]),
+            "missing": status.missing,
+            "dirty": status.dirty,
+            "migrations": migrations,
+            "repeatable": repeatables,
+        }));
+        return Ok(());
+    }
+    if status.current == 0 {
         print::success("No migration has been ran");
     }
-    for migration in migration_files.values() {
-        if migration.number == current {
-            print::success(&format!(
+    for migration in &status.migrations {
+        match migration.state {
+            _ if migration.number == status.current => print::success(&format!(
                 "{} - {} (current)",
                 migration.number, migration.name
-            ));
-        } else {
-            println!("{} - {}", migration.number, migration.name);
+            )),
+            MigrationState::OutOfOrder => print::error(&format!(
+                "{} - {} (not applied, out of order)",
+                migration.number, migration.name
+            )),
+            MigrationState::Modified => print::error(&format!(
+                "{} - {} (modified after being applied)",
+                migration.number, migration.name
+            )),
+            MigrationState::Applied | MigrationState::Pending => {
+                println!("{} - {}", migration.number, migration.name)
+            }
+        }
+    }
+    for migration in &status.repeatables {
+        match migration.state {
+            MigrationState::Pending => println!("R - {} (pending)", migration.name),
+            _ => println!("R - {}", migration.name),
         }
     }
+    if !status.missing.is_empty() {
+        print::error(&format!(
+            "Applied migrations have no files: {}",
+            join(&status.missing)
+        ));
+    }
+    if !status.dirty.is_empty() {
+        print::error(&format!(
+            "Migrations failed or were interrupted midway: {}",
+            join(&status.dirty)
+        ));
+        println!("Check the database, then rerun them or use `force` to record the right state");
+    }
+    let out_of_order = status
+        .migrations
+        .iter()
+        .any(|m| m.state == MigrationState::OutOfOrder);
+    if out_of_order {
+        println!();
+        println!("Migrations older than the current one were not applied: use `up --allow-out-of-order` to apply them");
+    }
     Ok(())
 }
 
-pub fn up(mut driver: Box<dyn Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number().max(0);
-    let max = migration_files.keys().cloned().max().unwrap_or(0);
-    if current == max {
-        print::success("Migrations are up-to-date");
-        return Ok(());
+/// Exit codes of `check`, from the least to the most serious
+pub const UP_TO_DATE: i32 = 0;
+pub const PENDING: i32 = 2;
+pub const DATABASE_AHEAD: i32 = 3;
+pub const MODIFIED: i32 = 4;
+pub const DIRTY: i32 = 5;
+
+fn numbers_in(status: &Status, states: &[MigrationState]) -> Vec<u64> {
+    status
+        .migrations
+        .iter()
+        .filter(|m| states.contains(&m.state))
+        .map(|m| m.number)
+        .collect()
+}
+
+fn pending_repeatables(status: &Status) -> Vec<String> {
+    status
+        .repeatables
+        .iter()
+        .filter(|m| m.state == MigrationState::Pending)
+        .map(|m| m.name.clone())
+        .collect()
+}
+
+fn join(numbers: &[u64]) -> String {
+    numbers
+        .iter()
+        .map(|n| n.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Compares the database with the migration files without changing anything
+/// and returns the exit code matching the most serious difference found
+pub fn check(migrator: &mut Migrator, format: Format) -> Result<i32> {
+    let status = migrator.status()?;
+    let pending = numbers_in(
+        &status,
+        &[MigrationState::Pending, MigrationState::OutOfOrder],
+    );
+    let modified = numbers_in(&status, &[MigrationState::Modified]);
+    let pending_repeatable = pending_repeatables(&status);
+
+    let (code, outcome) = if !status.dirty.is_empty() {
+        (DIRTY, "dirty")
+    } else if !modified.is_empty() {
+        (MODIFIED, "modified")
+    } else if !status.missing.is_empty() {
+        (DATABASE_AHEAD, "database_ahead")
+    } else if !pending.is_empty() || !pending_repeatable.is_empty() {
+        (PENDING, "pending")
+    } else {
+        (UP_TO_DATE, "up_to_date")
+    };
+
+    if format == Format::Json {
+        print::json(&json!({
+            "command": "check",
+            "status": outcome,
+            "exit_code": code,
+            "current": status.current,
+            "pending": pending,
+            "pending_repeatable": pending_repeatable,
+            "missing": status.missing,
+            "modified": modified,
+            "dirty": status.dirty,
+        }));
+        return Ok(code);
     }
 
-    for migration in migration_files.values() {
-        if migration.number > current {
-            migrate!(driver, migration, Direction::Up);
-        }
+    if code == UP_TO_DATE {
+        print::success("The database is up-to-date with the migration files");
     }
+    if !status.dirty.is_empty() {
+        print::error(&format!(
+            "Migrations failed or were interrupted midway: {}",
+            join(&status.dirty)
+        ));
+    }
+    if !modified.is_empty() {
+        print::error(&format!(
+            "Applied migrations were modified since they ran: {}",
+            join(&modified)
+        ));
+    }
+    if !status.missing.is_empty() {
+        print::error(&format!(
+            "Applied migrations have no files, the database is ahead: {}",
+            join(&status.missing)
+        ));
+    }
+    if !pending.is_empty() {
+        print::error(&format!("Migrations are pending: {}", join(&pending)));
+    }
+    if !pending_repeatable.is_empty() {
+        print::error(&format!(
+            "Repeatable migrations are pending: {}",
+            pending_repeatable.join(", ")
+        ));
+    }
+    Ok(code)
+}
+
+pub fn verify(migrator: &mut Migrator, format: Format) -> Result<()> {
+    migrator.verify()?;
+    if format == Format::Json {
+        print::json(&json!({"command": "verify"}));
+        return Ok(());
+    }
+    print::success("All applied migrations match their files");
     Ok(())
 }
 
-pub fn down(mut driver: Box<dyn Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number().max(0);
-    if current == 0 {
-        print::success("No down migrations to run");
+pub fn unlock(migrator: &mut Migrator, format: Format) -> Result<()> {
+    migrator.release_stale_lock()?;
+    if format == Format::Json {
+        print::json(&json!({"command": "unlock"}));
         return Ok(());
     }
+    print::success("Released the migration lock");
+    Ok(())
+}
 
-    let mut numbers: Vec<u32> = migration_files
-        .keys()
-        .cloned()
-        .filter(|i| i <= &current)
-        .collect();
-    numbers.sort_by(|a, b| b.cmp(a));
+/// Prints what a command ran, or would have ran for a dry run.
+/// In text mode migrations actually ran are printed by `print::Progress`
+/// as they go so only dry runs and empty reports print something here.
+fn print_report(
+    migrator: &mut Migrator,
+    command: &str,
+    report: &Report,
+    empty_message: &str,
+    format: Format,
+) -> Result<()> {
+    if format == Format::Json {
+        let migrations = report
+            .migrations
+            .iter()
+            .map(|step| {
+                if report.dry_run {
+                    let mut entry = report_entry(step);
+                    entry["sql"] = json!(migrator.content(step)?);
+                    Ok(entry)
+                } else {
+                    Ok(ran_entry(step))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let current = migrator.status()?.current;
+        print::json(&json!({
+            "command": command,
+            "dry_run": report.dry_run,
+            "current": current,
+            "migrations": migrations,
+        }));
+        return Ok(());
+    }
 
-    for number in numbers {
-        let migration = &migration_files[&number];
-        migrate!(driver, migration, Direction::Down);
+    if report.is_empty() {
+        print::success(empty_message);
+    } else if report.dry_run {
+        for step in &report.migrations {
+            let content = migrator.content(step)?;
+            print_dry_run(migrator.migration(step).unwrap(), step.direction, &content);
+        }
     }
     Ok(())
 }
 
-pub fn redo(mut driver: Box<dyn Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number().max(0);
-    if current == 0 {
-        print::success("No migration to redo");
+fn report_entry(step: &MigrationReport) -> Value {
+    json!({
+        "number": step.number,
+        "name": step.name,
+        "direction": step.direction.to_string(),
+        "repeatable": step.repeatable,
+    })
+}
+
+fn ran_entry(step: &MigrationReport) -> Value {
+    let mut entry = report_entry(step);
+    entry["duration_ms"] = json!(step.duration.as_millis() as u64);
+    entry
+}
+
+/// The JSON document printed when a command fails. A migration failing midway
+/// also lists the ones that ran before it.
+pub fn error_json(e: &Error) -> Value {
+    let causes: Vec<String> = e.iter().skip(1).map(|e| e.to_string()).collect();
+    let mut document = json!({"error": e.to_string(), "causes": causes});
+    if let ErrorKind::DbMigrateLib(LibErrorKind::MigrationFailed(ref report)) = *e.kind() {
+        let migrations: Vec<Value> = report.migrations.iter().map(ran_entry).collect();
+        document["migrations"] = json!(migrations);
+    }
+    document
+}
+
+fn print_dry_run(migration: &Migration, direction: Direction, content: &str) {
+    let transaction = if migration.in_transaction(direction) {
+        ""
+    } else {
+        " (outside of a transaction)"
+    };
+    if migration.repeatable {
+        print::success(&format!(
+            "Would run repeatable migration {}{}",
+            migration.get_filename(direction),
+            transaction
+        ));
+    } else {
+        print::success(&format!(
+            "Would run {} migration #{}: {}{}",
+            direction,
+            migration.number,
+            migration.get_filename(direction),
+            transaction
+        ));
+    }
+    println!("{}", content.trim_end());
+    println!();
+}
+
+pub fn up(migrator: &mut Migrator, steps: Option<usize>, format: Format) -> Result<()> {
+    let report = migrator.up(steps)?;
+    print_report(migrator, "up", &report, "Migrations are up-to-date", format)
+}
+
+pub fn down(migrator: &mut Migrator, steps: Option<usize>, format: Format) -> Result<()> {
+    let report = migrator.down(steps)?;
+    print_report(
+        migrator,
+        "down",
+        &report,
+        "No down migrations to run",
+        format,
+    )
+}
+
+pub fn redo(migrator: &mut Migrator, format: Format) -> Result<()> {
+    let report = migrator.redo()?;
+    print_report(migrator, "redo", &report, "No migration to redo", format)
+}
+
+pub fn revert(migrator: &mut Migrator, format: Format) -> Result<()> {
+    let report = migrator.revert()?;
+    print_report(
+        migrator,
+        "revert",
+        &report,
+        "No migration to revert",
+        format,
+    )
+}
+
+pub fn goto(migrator: &mut Migrator, target: u64, format: Format) -> Result<()> {
+    let report = migrator.to(target)?;
+    let empty_message = format!("Already at migration {}", target);
+    print_report(migrator, "goto", &report, &empty_message, format)
+}
+
+pub fn baseline(migrator: &mut Migrator, target: u64, force: bool, format: Format) -> Result<()> {
+    let report = migrator.baseline(target, force)?;
+    if format == Format::Json {
+        let migrations: Vec<Value> = report
+            .migrations
+            .iter()
+            .map(|m| json!({"number": m.number, "name": m.name}))
+            .collect();
+        print::json(&json!({
+            "command": "baseline",
+            "dry_run": report.dry_run,
+            "current": migrator.status()?.current,
+            "migrations": migrations,
+        }));
         return Ok(());
     }
-    let migration = &migration_files[&current];
 
-    migrate!(driver, migration, Direction::Down);
-    migrate!(driver, migration, Direction::Up);
+    if report.is_empty() {
+        print::success(&format!("Migrations up to {} are already applied", target));
+        return Ok(());
+    }
+    let verb = if report.dry_run {
+        "Would record"
+    } else {
+        "Recorded"
+    };
+    for migration in &report.migrations {
+        println!(
+            "{} migration #{}: {} as applied",
+            verb, migration.number, migration.name
+        );
+    }
+    if !report.dry_run {
+        print::success(&format!(
+            "Database baselined at migration {} without running any SQL",
+            target
+        ));
+    }
     Ok(())
 }
 
-pub fn revert(mut driver: Box<dyn Driver>, migration_files: &Migrations) -> Result<()> {
-    let current = driver.get_current_number().max(0);
-    if current == 0 {
-        print::success("No migration to revert");
+/// Asks for a confirmation on the terminal, anything but yes meaning no
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr()
+        .flush()
+        .chain_err(|| "Failed to ask for a confirmation")?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .chain_err(|| "Failed to read the confirmation")?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+pub fn force(migrator: &mut Migrator, target: u64, yes: bool, format: Format) -> Result<()> {
+    if !yes
+        && !confirm(&format!(
+            "This records the database as being at migration {} without running any SQL. Continue?",
+            target
+        ))?
+    {
+        bail!("Aborted, nothing was recorded");
+    }
+    let report = migrator.force(target)?;
+    if format == Format::Json {
+        let migrations: Vec<Value> = report
+            .migrations
+            .iter()
+            .map(|m| json!({"number": m.number, "name": m.name, "direction": m.direction.to_string()}))
+            .collect();
+        print::json(&json!({
+            "command": "force",
+            "dry_run": report.dry_run,
+            "current": migrator.status()?.current,
+            "migrations": migrations,
+        }));
         return Ok(());
     }
-    let migration = &migration_files[&current];
 
-    migrate!(driver, migration, Direction::Down);
+    if report.is_empty() {
+        print::success(&format!("Already at migration {}", target));
+        return Ok(());
+    }
+    let verb = if report.dry_run {
+        "Would record"
+    } else {
+        "Recorded"
+    };
+    for migration in &report.migrations {
+        let state = match migration.direction {
+            Direction::Up => "applied",
+            Direction::Down => "reverted",
+        };
+        println!(
+            "{} migration #{}: {} as {}",
+            verb, migration.number, migration.name, state
+        );
+    }
+    if !report.dry_run {
+        print::success(&format!("Database forced to migration {}", target));
+    }
     Ok(())
 }
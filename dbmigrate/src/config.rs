@@ -0,0 +1,251 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use dbmigrate_lib::Placeholders;
+use errors::{Result, ResultExt};
+use toml;
+
+/// Name of the configuration file, looked for in the working directory and its parents
+pub const CONFIG_FILENAME: &str = "dbmigrate.toml";
+
+/// Prefix of the environment variables setting placeholder values,
+/// `DBMIGRATE_VAR_app_role` setting `${app_role}`
+const VAR_ENV_PREFIX: &str = "DBMIGRATE_VAR_";
+
+/// Settings of a single environment, all optional as flags and environment
+/// variables can provide them instead
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Environment {
+    pub url: Option<String>,
+    /// Relative to the directory of the configuration file
+    pub path: Option<PathBuf>,
+    pub lock_timeout: Option<u64>,
+    /// Name of the table storing the migration history
+    pub table: Option<String>,
+    /// Postgres schema of that table
+    pub schema: Option<String>,
+    /// Values of the placeholders of the migration files
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Environment used when `--env` isn't given
+    default_env: Option<String>,
+    #[serde(default)]
+    environments: BTreeMap<String, Environment>,
+}
+
+/// Finds the closest configuration file, starting from `dir`
+fn find_config_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(CONFIG_FILENAME))
+        .find(|file| file.is_file())
+}
+
+fn parse(content: &str, file: &Path) -> Result<Config> {
+    toml::from_str(content).chain_err(|| format!("Invalid {}", file.display()))
+}
+
+/// Picks an environment out of a configuration file content, making its path
+/// relative to the directory of the file
+fn select(content: &str, file: &Path, name: Option<&str>) -> Result<Environment> {
+    let config = parse(content, file)?;
+    let name = match name.or(config.default_env.as_deref()) {
+        Some(n) => n,
+        None => return Ok(Environment::default()),
+    };
+    let mut environment = match config.environments.get(name) {
+        Some(e) => e.clone(),
+        None => {
+            let available: Vec<&str> = config.environments.keys().map(|k| k.as_str()).collect();
+            bail!(
+                "Environment `{}` is not defined, available ones are: {}",
+                name,
+                available.join(", ")
+            );
+        }
+    };
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    environment.path = environment.path.map(|p| dir.join(p));
+    Ok(environment)
+}
+
+/// Loads the settings of the environment `name`, or of the default one if
+/// there is a configuration file defining it.
+/// Without a configuration file, asking for an environment is an error.
+pub fn load(name: Option<&str>) -> Result<Environment> {
+    let cwd = env::current_dir().chain_err(|| "Failed to get the working directory")?;
+    let file = match find_config_file(&cwd) {
+        Some(f) => f,
+        None => {
+            if let Some(name) = name {
+                bail!(
+                    "Environment `{}` was asked for but no {} was found",
+                    name,
+                    CONFIG_FILENAME
+                );
+            }
+            return Ok(Environment::default());
+        }
+    };
+    let content =
+        fs::read_to_string(&file).chain_err(|| format!("Failed to read {}", file.display()))?;
+    select(&content, &file, name)
+}
+
+/// Picks a setting from, by decreasing priority, its command arg, its environment
+/// variable and the configuration file
+pub fn resolve<T: From<String>>(
+    arg: Option<&str>,
+    env_value: Option<String>,
+    config: Option<T>,
+) -> Option<T> {
+    arg.map(|a| T::from(a.to_string()))
+        .or(env_value.map(T::from))
+        .or(config)
+}
+
+/// Gathers the placeholder values: `--var` arguments override the environment
+/// variables, which override the ones of dbmigrate.toml
+pub fn placeholders<I>(
+    config_vars: BTreeMap<String, String>,
+    env_vars: I,
+    var_args: &[&str],
+) -> Result<Placeholders>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut values = config_vars;
+    for (key, value) in env_vars {
+        if let Some(name) = key.strip_prefix(VAR_ENV_PREFIX) {
+            values.insert(name.to_string(), value);
+        }
+    }
+    for var in var_args {
+        match var.split_once('=') {
+            Some((key, value)) if !key.is_empty() => {
+                values.insert(key.to_string(), value.to_string());
+            }
+            _ => bail!("Invalid --var `{}`, expected key=value", var),
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_config_file, placeholders, resolve, select, CONFIG_FILENAME};
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use tempdir::TempDir;
+
+    const CONFIG: &str = r#"
+default_env = "dev"
+
+[environments.dev]
+url = "sqlite:///tmp/dev.sqlite"
+path = "migrations"
+
+[environments.prod]
+url = "postgres://prod"
+lock_timeout = 10
+
+[environments.prod.vars]
+schema = "billing"
+"#;
+
+    #[test]
+    fn test_select_default_env() {
+        let file = Path::new("/project/dbmigrate.toml");
+        let environment = select(CONFIG, file, None).unwrap();
+        assert_eq!(environment.url.as_deref(), Some("sqlite:///tmp/dev.sqlite"));
+
+        let environment = select(CONFIG, file, Some("prod")).unwrap();
+        assert_eq!(environment.url.as_deref(), Some("postgres://prod"));
+        assert_eq!(environment.lock_timeout, Some(10));
+        assert_eq!(environment.vars["schema"], "billing");
+
+        // Nothing to pick without a default
+        let environment = select("[environments.dev]\nurl = \"a\"", file, None).unwrap();
+        assert_eq!(environment.url, None);
+    }
+
+    #[test]
+    fn test_select_unknown_env() {
+        let err = select(CONFIG, Path::new("dbmigrate.toml"), Some("staging")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment `staging` is not defined, available ones are: dev, prod"
+        );
+    }
+
+    #[test]
+    fn test_path_relative_to_config_file() {
+        let environment = select(CONFIG, Path::new("/project/dbmigrate.toml"), None).unwrap();
+        assert_eq!(environment.path, Some(PathBuf::from("/project/migrations")));
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let file = Path::new("dbmigrate.toml");
+        assert!(select("[environments.dev]\nurll = \"a\"", file, Some("dev")).is_err());
+        assert!(select("default_environment = \"dev\"", file, None).is_err());
+    }
+
+    #[test]
+    fn test_find_config_file() {
+        let dir = TempDir::new("config").unwrap();
+        let nested = dir.path().join("a").join("b");
+        fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_config_file(&nested), None);
+
+        let file = dir.path().join(CONFIG_FILENAME);
+        fs::write(&file, CONFIG).unwrap();
+        assert_eq!(find_config_file(&nested), Some(file));
+    }
+
+    #[test]
+    fn test_resolve_precedence() {
+        let config = Some("toml".to_string());
+        let env_value = Some("env".to_string());
+        assert_eq!(
+            resolve(Some("arg"), env_value.clone(), config.clone()),
+            Some("arg".to_string())
+        );
+        assert_eq!(
+            resolve(None, env_value, config.clone()),
+            Some("env".to_string())
+        );
+        assert_eq!(resolve(None, None, config), Some("toml".to_string()));
+        assert_eq!(resolve::<String>(None, None, None), None);
+    }
+
+    #[test]
+    fn test_placeholders_precedence() {
+        let mut config_vars = BTreeMap::new();
+        config_vars.insert("schema".to_string(), "toml".to_string());
+        config_vars.insert("role".to_string(), "toml".to_string());
+        config_vars.insert("owner".to_string(), "toml".to_string());
+        let env_vars = vec![
+            ("DBMIGRATE_VAR_role".to_string(), "env".to_string()),
+            ("DBMIGRATE_VAR_owner".to_string(), "env".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+
+        let values = placeholders(config_vars.clone(), env_vars, &["owner=arg=1"]).unwrap();
+        assert_eq!(values["schema"], "toml");
+        assert_eq!(values["role"], "env");
+        assert_eq!(values["owner"], "arg=1");
+        assert_eq!(values.len(), 3);
+
+        assert!(placeholders(config_vars.clone(), vec![], &["owner"]).is_err());
+        assert!(placeholders(config_vars, vec![], &["=arg"]).is_err());
+    }
+}
@@ -1,126 +1,312 @@
 //! CLI to manage SQL migrations for Postgres, MySQL and SQLite
 //!
+#![allow(clippy::result_large_err)]
 
 #[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate serde_derive;
 extern crate dbmigrate_lib;
 extern crate dotenv;
+#[macro_use]
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempdir;
 extern crate term;
+extern crate toml;
 
+use clap::ArgMatches;
 use std::env;
-use std::path::Path;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 mod cmd;
+mod config;
 mod errors;
 mod print;
 
-use dbmigrate_lib::{get_driver, read_migration_files};
+use dbmigrate_lib::{
+    detect_width, driver_scheme, get_driver, get_driver_with_table, read_all_migration_files,
+    read_migration_files, read_migration_files_for_driver, read_repeatable_migrations,
+    MigrationTable, Migrator, Numbering, DEFAULT_LOCK_TIMEOUT, DEFAULT_TABLE_NAME,
+};
 use errors::{Result, ResultExt};
+use print::Format;
 
 fn main() {
-    if let Err(ref e) = run() {
-        print::error(&format!("{}", e));
-        for e in e.iter().skip(1) {
-            print::error(&format!("caused by: {}", e));
+    dotenv::dotenv().ok();
+    let matches = parse_args();
+    let format = Format::from_arg(matches.value_of("format"));
+
+    if let Err(ref e) = run(&matches, format) {
+        match format {
+            Format::Json => print::json(&cmd::error_json(e)),
+            Format::Text => {
+                print::error(&format!("{}", e));
+                for e in e.iter().skip(1) {
+                    print::error(&format!("caused by: {}", e));
+                }
+            }
         }
 
         ::std::process::exit(1);
     }
 }
 
-fn run() -> Result<()> {
-    dotenv::dotenv().ok();
+/// Gets the optional `--steps` value of a subcommand, exiting if it isn't a number
+fn steps_arg(matches: &ArgMatches, subcommand: &str) -> Option<usize> {
+    let sub_matches = matches.subcommand_matches(subcommand)?;
+    if sub_matches.is_present("steps") {
+        Some(value_t_or_exit!(sub_matches, "steps", usize))
+    } else {
+        None
+    }
+}
 
-    let matches = clap_app!(dbmigrate =>
+fn parse_args() -> ArgMatches<'static> {
+    clap_app!(dbmigrate =>
         (@setting SubcommandRequiredElseHelp)
-        (version: &crate_version!()[..])
+        (version: crate_version!())
         (author: "Vincent Prouillet <vincent@wearewizards.io>")
         (about: "
 Handles migrations for databases.
 Each call requires the database url and the path to the directory containing
 the SQL migration files.
 Those can be set using the DBMIGRATE_URL and DBMIGRATE_PATH environment
-variables, via a .env file, or the --url and --path arguments.
-Using arguments will override the environment variables.
+variables, via a .env file, the --url and --path arguments or an environment
+of a dbmigrate.toml file selected with --env.
+Arguments override the environment variables, which override dbmigrate.toml.
         ")
         (@arg url: -u --url +takes_value "Sets the URL of the database to use.")
         (@arg path: -p --path +takes_value "Sets the folder containing the migrations")
+        (@arg table: --table +takes_value "Sets the name of the table storing the migration history [default: __dbmigrate_table]")
+        (@arg schema: --schema +takes_value "Sets the Postgres schema of the table storing the migration history")
+        (@arg env: -e --env +takes_value "Sets which environment of dbmigrate.toml to use")
+        (@arg lock_timeout: --("lock-timeout") +takes_value
+            "Sets how many seconds to wait for another dbmigrate process to release the migration lock [default: 60]")
+        (@arg var: --var +takes_value +multiple number_of_values(1)
+            "Sets the value of a ${key} placeholder of the migration files, as key=value. Can be repeated")
+        (@arg format: --format +takes_value possible_value[text json] default_value("text")
+            "Sets the output format: colored text or a JSON document for scripts")
         (@subcommand create =>
             (about: "Creates two migration files (up and down) with the given slug")
             (@arg slug: +required "Sets the name of the migration. `.` (dot) is not allowed in the name")
+            (@arg timestamp: --timestamp "Number the migration with the current UTC time (YYYYMMDDHHMMSS) instead of the next number")
+            (@arg width: --width +takes_value "Sets how many digits the migration number is padded to. Defaults to the width of the latest migration")
+        )
+        (@subcommand renumber =>
+            (about: "Renames all the migration files so their numbers are padded to the given width, without changing the numbers")
+            (@arg width: +required "Sets how many digits the migration numbers are padded to")
         )
         (@subcommand status =>
             (about: "See list of migrations and which ones are applied")
         )
+        (@subcommand check =>
+            (about: "Compare the database with the migration files without applying anything. Exits with 0 if up-to-date, 2 if migrations are pending, 3 if the database is ahead of the files, 4 if applied migrations were modified and 5 if migrations failed or were interrupted midway")
+            (visible_alias: "pending")
+        )
+        (@subcommand verify =>
+            (about: "Check that applied migrations were not modified since they ran")
+        )
         (@subcommand up =>
             (about: "Apply all non-applied migrations")
+            (@arg steps: --steps +takes_value "Only apply the next N migrations")
+            (@arg allow_out_of_order: --("allow-out-of-order") "Also apply migrations older than the current one that were not applied yet")
+            (@arg dry_run: --("dry-run") "Print the migrations that would run and their SQL without running them")
         )
         (@subcommand down =>
             (about: "Un-apply all applied migrations")
+            (@arg steps: --steps +takes_value "Only un-apply the last N migrations")
+            (@arg dry_run: --("dry-run") "Print the migrations that would run and their SQL without running them")
         )
         (@subcommand redo =>
             (about: "Rollback the current migration and re-run it")
+            (@arg dry_run: --("dry-run") "Print the migrations that would run and their SQL without running them")
         )
         (@subcommand revert =>
             (about: "Revert the current migration")
+            (@arg dry_run: --("dry-run") "Print the migrations that would run and their SQL without running them")
+        )
+        (@subcommand baseline =>
+            (about: "Record the migrations up to the given one as applied without running them, for databases that already match them")
+            (@arg number: +required "Sets the number of the last migration the database already matches")
+            (@arg force: --force "Record them even if the database already has applied migrations")
+            (@arg dry_run: --("dry-run") "Print the migrations that would be recorded without recording them")
+        )
+        (@subcommand force =>
+            (about: "Record the given migration as the current one without running anything, to repair the history after a failed migration, 0 reverting all of them")
+            (@arg number: +required "Sets the number of the migration the database is actually at")
+            (@arg yes: -y --yes "Don't ask for a confirmation")
+            (@arg dry_run: --("dry-run") "Print the migrations that would be recorded without recording them")
+        )
+        (@subcommand unlock =>
+            (about: "Release the migration lock left behind by a dbmigrate process killed while holding it (SQLite and SurrealDB only, other databases release it by themselves). Only use it when no other dbmigrate process is running")
         )
-    ).get_matches();
+        (@subcommand goto =>
+            (about: "Apply or revert migrations until the given one is the current one, 0 reverting all of them")
+            (@arg number: +required "Sets the number of the migration to go to")
+            (@arg allow_out_of_order: --("allow-out-of-order") "Also apply migrations older than the current one that were not applied yet")
+            (@arg dry_run: --("dry-run") "Print the migrations that would run and their SQL without running them")
+        )
+    ).get_matches()
+}
 
-    let path_value = match matches
-        .value_of("path")
-        .map(|s| s.into())
-        .or(env::var("DBMIGRATE_PATH").ok())
-    {
+fn run(matches: &ArgMatches, format: Format) -> Result<()> {
+    let environment = config::load(matches.value_of("env"))?;
+
+    let path_value = match config::resolve(
+        matches.value_of("path"),
+        env::var("DBMIGRATE_PATH").ok(),
+        environment.path,
+    ) {
         Some(u) => u,
-        None => bail!("No migration path was provided in the environment or via a command arg."),
+        None => bail!("No migration path was provided in the environment, via a command arg or in dbmigrate.toml."),
     };
-    let path = Path::new(&path_value);
+    let path = path_value.as_path();
 
-    let migration_files = read_migration_files(path)?;
+    // Checks the files for every driver by itself
+    if let Some(sub_matches) = matches.subcommand_matches("renumber") {
+        let width = value_t_or_exit!(sub_matches, "width", usize);
+        match cmd::renumber(path, width, format) {
+            Ok(_) => std::process::exit(0),
+            Err(e) => return Err(e),
+        }
+    }
 
+    // Numbered after the files of every driver, it doesn't need a database
     if let Some("create") = matches.subcommand_name() {
+        let migration_files = read_all_migration_files(path)?;
         // Should be safe unwraps
-        let slug = matches
-            .subcommand_matches("create")
-            .unwrap()
-            .value_of("slug")
-            .unwrap();
-        match cmd::create(&migration_files, path, slug) {
+        let sub_matches = matches.subcommand_matches("create").unwrap();
+        let slug = sub_matches.value_of("slug").unwrap();
+        // Directories already using timestamps keep using them
+        let numbering = if sub_matches.is_present("timestamp") {
+            Numbering::Timestamp
+        } else {
+            Numbering::detect(&migration_files)
+        };
+        let width = if sub_matches.is_present("width") {
+            value_t_or_exit!(sub_matches, "width", usize)
+        } else {
+            detect_width(&migration_files)
+        };
+        match cmd::create(&migration_files, path, slug, numbering, width, format) {
             Ok(_) => std::process::exit(0),
             Err(e) => return Err(e),
         }
     }
 
-    let url = match matches
-        .value_of("url")
-        .map(|s| s.into())
-        .or(env::var("DBMIGRATE_URL").ok())
-    {
+    let url = config::resolve(
+        matches.value_of("url"),
+        env::var("DBMIGRATE_URL").ok(),
+        environment.url,
+    );
+    let url = match url {
         Some(u) => u,
-        None => bail!("No database url was provided in the environment or via a command arg."),
+        None => bail!("No database url was provided in the environment, via a command arg or in dbmigrate.toml."),
+    };
+    // Files specific to the driver of the URL replace the generic ones
+    let migration_files = match driver_scheme(&url) {
+        Ok(driver) => read_migration_files_for_driver(path, &driver)?,
+        Err(_) => read_migration_files(path)?,
+    };
+    // Without any of those, the table can be set in the URL query string
+    let table_name = matches
+        .value_of("table")
+        .map(String::from)
+        .or(environment.table);
+    let schema = matches
+        .value_of("schema")
+        .map(String::from)
+        .or(environment.schema);
+    let driver = if table_name.is_some() || schema.is_some() {
+        let mut table = MigrationTable::new(table_name.as_deref().unwrap_or(DEFAULT_TABLE_NAME))?;
+        if let Some(schema) = schema {
+            table = table.schema(&schema)?;
+        }
+        get_driver_with_table(&url, table)
+    } else {
+        get_driver(&url)
+    }
+    .chain_err(|| "Failed to get DB connection")?;
+    let lock_timeout = if matches.is_present("lock_timeout") {
+        Duration::from_secs(value_t_or_exit!(matches, "lock_timeout", u64))
+    } else {
+        environment
+            .lock_timeout
+            .map_or(DEFAULT_LOCK_TIMEOUT, Duration::from_secs)
     };
-    let driver = get_driver(&url).chain_err(|| "Failed to get DB connection")?;
 
     let start = Instant::now();
 
-    match matches.subcommand_name() {
-        Some("status") => cmd::status(driver, &migration_files)?,
-        Some("up") => cmd::up(driver, &migration_files)?,
-        Some("down") => cmd::down(driver, &migration_files)?,
-        Some("redo") => cmd::redo(driver, &migration_files)?,
-        Some("revert") => cmd::revert(driver, &migration_files)?,
+    let subcommand = matches.subcommand_name();
+    let sub_matches = subcommand.and_then(|name| matches.subcommand_matches(name));
+    let steps = subcommand.and_then(|name| steps_arg(matches, name));
+    let dry_run = sub_matches.is_some_and(|m| m.is_present("dry_run"));
+    let allow_out_of_order = sub_matches.is_some_and(|m| m.is_present("allow_out_of_order"));
+
+    let repeatables = read_repeatable_migrations(path)?;
+    let var_args: Vec<&str> = matches.values_of("var").into_iter().flatten().collect();
+    let values = config::placeholders(environment.vars, env::vars(), &var_args)?;
+    // Only the migrations about to run need values for their placeholders
+    let mut migrator = Migrator::new(driver, migration_files)
+        .repeatable_migrations(repeatables)
+        .placeholders(values);
+    // The JSON document is printed once the command is done
+    if format == Format::Text {
+        migrator = migrator.observer(Box::new(print::Progress));
+    }
+    // Commands changing the database hold the lock for their whole run so
+    // concurrent dbmigrate processes don't step on each other
+    let mut migrator = migrator
+        .lock_timeout(Some(lock_timeout))
+        .allow_out_of_order(allow_out_of_order)
+        .dry_run(dry_run);
+
+    match subcommand {
+        Some("status") => cmd::status(&mut migrator, format)?,
+        Some("check") => {
+            let code = cmd::check(&mut migrator, format)?;
+            std::process::exit(code)
+        }
+        Some("verify") => cmd::verify(&mut migrator, format)?,
+        Some("unlock") => cmd::unlock(&mut migrator, format)?,
+        Some("up") => cmd::up(&mut migrator, steps, format)?,
+        Some("down") => cmd::down(&mut migrator, steps, format)?,
+        Some("redo") => cmd::redo(&mut migrator, format)?,
+        Some("revert") => cmd::revert(&mut migrator, format)?,
+        Some("goto") => {
+            let goto_matches = sub_matches.unwrap();
+            let target = value_t_or_exit!(goto_matches, "number", u64);
+            cmd::goto(&mut migrator, target, format)?
+        }
+        Some("force") => {
+            let force_matches = sub_matches.unwrap();
+            let target = value_t_or_exit!(force_matches, "number", u64);
+            // Nothing to confirm for a dry run
+            let yes = force_matches.is_present("yes") || dry_run;
+            cmd::force(&mut migrator, target, yes, format)?
+        }
+        Some("baseline") => {
+            let baseline_matches = sub_matches.unwrap();
+            let target = value_t_or_exit!(baseline_matches, "number", u64);
+            let force = baseline_matches.is_present("force");
+            cmd::baseline(&mut migrator, target, force, format)?
+        }
         None => println!("No subcommand was used"),
         _ => println!("Some other subcommand was used"),
     }
 
+    if format == Format::Json {
+        return Ok(());
+    }
+
     let duration = start.elapsed();
     let minutes = duration.as_secs() / 60;
     let seconds = duration.as_secs() % 60;
     // Spacing
-    println!("");
+    println!();
     if minutes == 0 && seconds == 0 {
         println!("Operation took less than 1 second");
     } else {
@@ -1,8 +1,32 @@
-use std::io;
 use std::io::prelude::*;
+use std::time::Duration;
 
+use dbmigrate_lib::{Direction, Migration, Observer};
+use serde_json::{self, Value};
 use term;
 
+/// How the commands print their results
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Colored messages for humans
+    Text,
+    /// A single JSON document on stdout, for scripts
+    Json,
+}
+
+impl Format {
+    pub fn from_arg(value: Option<&str>) -> Format {
+        match value {
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+}
+
+pub fn json(document: &Value) {
+    println!("{}", serde_json::to_string_pretty(document).unwrap());
+}
+
 pub fn error(message: &str) {
     if let Some(mut t) = term::stderr() {
         match t.fg(term::color::BRIGHT_RED) {
@@ -13,7 +37,7 @@ pub fn error(message: &str) {
             Err(_) => writeln!(t, "{}", message).unwrap(),
         };
     } else {
-        writeln!(io::stderr(), "{}", message).unwrap();
+        eprintln!("{}", message);
     }
 }
 
@@ -30,3 +54,23 @@ pub fn success(message: &str) {
         println!("{}", message);
     }
 }
+
+/// Prints the progress of the migrations as they run
+pub struct Progress;
+
+impl Observer for Progress {
+    fn migration_started(&mut self, migration: &Migration, direction: Direction) {
+        if migration.repeatable {
+            println!("Running repeatable migration {}", migration.name);
+            return;
+        }
+        println!(
+            "Running {} migration #{}: {}",
+            direction, migration.number, migration.name
+        );
+    }
+
+    fn migration_finished(&mut self, _: &Migration, _: Direction, duration: Duration) {
+        success(&format!("> Done in {} second(s)", duration.as_secs()));
+    }
+}
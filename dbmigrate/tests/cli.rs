@@ -0,0 +1,35 @@
+extern crate tempdir;
+
+use std::fs;
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+/// Runs the binary in `dir`, away from any `dbmigrate.toml` or `.env`
+fn dbmigrate(dir: &TempDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_dbmigrate"))
+        .current_dir(dir.path())
+        .env_remove("DBMIGRATE_URL")
+        .env_remove("DBMIGRATE_PATH")
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_create_after_driver_specific_migrations() {
+    let dir = TempDir::new("dbmigrate").unwrap();
+    let migrations = dir.path().join("migrations");
+    fs::create_dir(&migrations).unwrap();
+    fs::write(migrations.join("0001.init.up.sql"), "SELECT 1;").unwrap();
+    fs::write(migrations.join("0002.index.postgres.up.sql"), "SELECT 1;").unwrap();
+
+    let output = dbmigrate(&dir, &["--path", "migrations", "create", "users"]);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(migrations.join("0003.users.up.sql").exists());
+    assert!(migrations.join("0003.users.down.sql").exists());
+}